@@ -0,0 +1,501 @@
+use crate::data_source::DataSource;
+use crate::domain::{self, ChangelistEvent, ChangelistEventKind, ChangelistFile};
+use crate::file_json_persistence;
+use crate::name_history::NameStyle;
+use crate::persistence::{LoadError, Persistence};
+use crate::snapshot_archive::{self, SnapshotArchive};
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// The versioned changelist, as stored by the JSON backend.
+    V2,
+    /// The legacy format read by the frontend.
+    Legacy,
+}
+
+/// Prints events to standard output, one JSON object per line.
+pub fn print_events(events: &[ChangelistEvent]) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    for event in events {
+        serde_json::to_writer(&mut stdout, event)?;
+        writeln!(stdout)?;
+    }
+
+    Ok(())
+}
+
+/// Prints the top entries of a leaderboard, with player names resolved.
+pub async fn fetch_level(
+    data_source: &impl DataSource,
+    leaderboard_name: &str,
+    entries: i32,
+) -> Result<()> {
+    let mut response = data_source
+        .get_leaderboard_range(leaderboard_name, 1, entries)
+        .await?;
+    let names = data_source
+        .resolve_steam_names(response.entries.iter().map(|x| x.steam_id).collect())
+        .await?;
+    for (entry, name) in response.entries.iter_mut().zip(names) {
+        entry.player_name = name;
+    }
+
+    serde_json::to_writer_pretty(io::stdout().lock(), &response)?;
+    println!();
+
+    Ok(())
+}
+
+pub fn export(
+    persistence: &dyn Persistence,
+    format: ExportFormat,
+    names: NameStyle,
+    output: Option<&Path>,
+) -> Result<()> {
+    let mut events = persistence.load_changelist()?;
+    match persistence.load_name_history() {
+        Ok(name_history) => name_history.render(&mut events, names),
+        Err(LoadError::DoesNotExist) => {}
+        Err(e) => return Err(e.into()),
+    }
+    let data = match format {
+        ExportFormat::V2 => serde_json::to_vec_pretty(&ChangelistFile {
+            events,
+            ..ChangelistFile::default()
+        })?,
+        ExportFormat::Legacy => serde_json::to_vec_pretty(&domain::legacy_changelist(&events))?,
+    };
+
+    match output {
+        Some(path) => file_json_persistence::write_atomically(&data, path)?,
+        None => io::stdout().lock().write_all(&data)?,
+    }
+
+    Ok(())
+}
+
+/// Checks the stored data, logging every problem found.
+pub fn verify(persistence: &dyn Persistence) -> Result<()> {
+    let problems = find_problems(persistence)?;
+    for problem in &problems {
+        error!("{problem}");
+    }
+
+    if !problems.is_empty() {
+        bail!("found {} problems in the stored data", problems.len());
+    }
+    info!("No problems found");
+
+    Ok(())
+}
+
+fn find_problems(persistence: &dyn Persistence) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+
+    match persistence.load_query_results() {
+        Ok(query_results) => {
+            let mut names = HashSet::new();
+            for level in &query_results {
+                if !names.insert(level.leaderboard_name.as_str()) {
+                    problems.push(format!(
+                        "query results: {} appears more than once",
+                        level.leaderboard_name
+                    ));
+                }
+                let entries = &level.leaderboard_response.entries;
+                if entries
+                    .windows(2)
+                    .any(|x| x[0].global_rank > x[1].global_rank)
+                {
+                    problems.push(format!(
+                        "query results: the entries of {} aren't sorted by rank",
+                        level.leaderboard_name
+                    ));
+                }
+            }
+        }
+        Err(LoadError::DoesNotExist) => {}
+        Err(e) => problems.push(format!("query results: {e:#}")),
+    }
+
+    match persistence.load_changelist() {
+        Ok(changelist) => {
+            for (i, event) in changelist.iter().enumerate() {
+                let records = (event.new_record.is_some(), event.old_record.is_some());
+                let consistent = match event.kind {
+                    ChangelistEventKind::NewRecord => records == (true, true),
+                    ChangelistEventKind::FirstRecord => records == (true, false),
+                    ChangelistEventKind::RecordRemoved => records.1,
+                    ChangelistEventKind::LevelPublished | ChangelistEventKind::LevelRemoved => true,
+                    ChangelistEventKind::NewTopEntry => records.0 && event.rank.is_some(),
+                    ChangelistEventKind::PersonalBest => {
+                        records == (true, true) && event.rank.is_some()
+                    }
+                };
+                if !consistent {
+                    problems.push(format!(
+                        "changelist: event {i} on {} has the wrong records for a {:?} event",
+                        event.map_name, event.kind
+                    ));
+                }
+                if i > 0 && event.fetch_time < changelist[i - 1].fetch_time {
+                    problems.push(format!(
+                        "changelist: event {i} on {} is older than the event before it",
+                        event.map_name
+                    ));
+                }
+            }
+        }
+        Err(LoadError::DoesNotExist) => {}
+        Err(e) => problems.push(format!("changelist: {e:#}")),
+    }
+
+    match persistence.load_outbox() {
+        Ok(outbox) => {
+            let mut ids = HashSet::new();
+            for notification in &outbox {
+                if !ids.insert(notification.id.as_str()) {
+                    problems.push(format!(
+                        "outbox: notification {} appears more than once",
+                        notification.id
+                    ));
+                }
+            }
+        }
+        Err(e) => problems.push(format!("outbox: {e:#}")),
+    }
+
+    match persistence.load_record_history() {
+        Ok(_) | Err(LoadError::DoesNotExist) => {}
+        Err(e) => problems.push(format!("record history: {e:#}")),
+    }
+
+    match persistence.load_name_history() {
+        Ok(_) | Err(LoadError::DoesNotExist) => {}
+        Err(e) => problems.push(format!("name history: {e:#}")),
+    }
+
+    Ok(problems)
+}
+
+/// Prints the changelist events an update would produce going from the `old` snapshot to the
+/// `new` one.
+pub fn diff(old: &Path, new: &Path, rank_events_top: Option<i32>) -> Result<()> {
+    let old = snapshot_archive::read_snapshot(old)?;
+    let mut new = snapshot_archive::read_snapshot(new)?;
+
+    let now = new
+        .iter()
+        .map(|level| level.timestamp)
+        .max()
+        .unwrap_or_else(Utc::now);
+    let mut events = Vec::new();
+    crate::update_changelist(&mut events, &mut new, old, rank_events_top, now);
+
+    print_events(&events)
+}
+
+/// Recomputes the changelist events between the first and last archived snapshot in the range,
+/// and puts them in place of the stored ones.
+///
+/// Consecutive snapshots more than `max_gap` apart are refused, since the updates between them were
+/// pruned from the archive and replaying would merge their events into one update's. `None` allows
+/// any gap.
+pub fn replay(
+    persistence: &dyn Persistence,
+    archive: &SnapshotArchive,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    rank_events_top: Option<i32>,
+    max_gap: Option<Duration>,
+    dry_run: bool,
+) -> Result<()> {
+    let changelist = match persistence.load_changelist() {
+        Ok(x) => x,
+        Err(LoadError::DoesNotExist) => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let times: Vec<_> = archive
+        .list()?
+        .into_iter()
+        .filter(|time| from.is_none_or(|from| *time >= from) && to.is_none_or(|to| *time <= to))
+        .collect();
+    let (Some(&first), Some(&last)) = (times.first(), times.last()) else {
+        bail!("there are no archived snapshots in that range");
+    };
+    if let Some(max_gap) = max_gap {
+        for pair in times.windows(2) {
+            let gap = (pair[1] - pair[0]).to_std().unwrap_or_default();
+            if gap > max_gap {
+                bail!(
+                    "the snapshots from {} and {} are {} apart, so the updates in between were \
+                     pruned and their events would be merged; pass --allow-gaps to replay anyway",
+                    pair[0],
+                    pair[1],
+                    humantime::format_duration(gap)
+                );
+            }
+        }
+    }
+    info!("Replaying {} snapshots from {first} to {last}", times.len());
+
+    let (replayed, replaced) = replay_changelist(&changelist, archive, &times, rank_events_top)?;
+    info!(
+        "Replaced {} events with {} recomputed ones",
+        replaced.len(),
+        replayed.len()
+    );
+    if dry_run {
+        return print_events(&replayed);
+    }
+
+    let mut events: Vec<_> = changelist
+        .iter()
+        .filter(|event| event.fetch_time <= first)
+        .cloned()
+        .collect();
+    events.extend(replayed);
+    events.extend(
+        changelist
+            .into_iter()
+            .filter(|event| event.fetch_time > last),
+    );
+
+    persistence.replace_changelist(&events)
+}
+
+/// Runs the changelist computation over consecutive snapshots, returning the recomputed events
+/// and the stored ones they replace.
+///
+/// Events from updates after the first snapshot, up to and including the last one, are
+/// recomputed. Earlier events are kept, including for recognizing duplicates, just like during the
+/// updates themselves.
+fn replay_changelist(
+    changelist: &[ChangelistEvent],
+    archive: &SnapshotArchive,
+    times: &[DateTime<Utc>],
+    rank_events_top: Option<i32>,
+) -> Result<(Vec<ChangelistEvent>, Vec<ChangelistEvent>)> {
+    let (first, last) = (times[0], times[times.len() - 1]);
+    let mut events: Vec<_> = changelist
+        .iter()
+        .filter(|event| event.fetch_time <= first)
+        .cloned()
+        .collect();
+    let kept = events.len();
+
+    let mut previous = archive.load(first)?;
+    for &time in &times[1..] {
+        let mut snapshot = archive.load(time)?;
+        crate::update_changelist(&mut events, &mut snapshot, previous, rank_events_top, time);
+        previous = snapshot;
+    }
+
+    let replaced = changelist
+        .iter()
+        .filter(|event| event.fetch_time > first && event.fetch_time <= last)
+        .cloned()
+        .collect();
+
+    Ok((events.split_off(kept), replaced))
+}
+
+#[test]
+fn test_find_problems() {
+    use crate::domain::ChangelistRecord;
+    use crate::in_memory_persistence::InMemoryPersistence;
+    use distance_util::LeaderboardGameMode;
+
+    let record = |steam_id| ChangelistRecord {
+        steam_id,
+        player_name: String::new(),
+        score: 60_000,
+    };
+    let event = |kind, new_record, old_record| ChangelistEvent {
+        kind,
+        leaderboard_name: None,
+        map_name: "Level".into(),
+        mode: LeaderboardGameMode::Sprint,
+        map_author: None,
+        map_preview: None,
+        workshop_item_id: None,
+        steam_id_author: None,
+        new_record,
+        old_record,
+        rank: None,
+        fetch_time: Utc::now(),
+    };
+
+    let persistence = InMemoryPersistence::default();
+    assert!(find_problems(&persistence).unwrap().is_empty());
+
+    persistence
+        .append_changelist(
+            &[
+                event(ChangelistEventKind::FirstRecord, Some(record(1)), None),
+                event(
+                    ChangelistEventKind::NewRecord,
+                    Some(record(2)),
+                    Some(record(1)),
+                ),
+                event(ChangelistEventKind::NewRecord, Some(record(3)), None),
+            ],
+            &[],
+        )
+        .unwrap();
+    assert_eq!(
+        find_problems(&persistence).unwrap(),
+        ["changelist: event 2 on Level has the wrong records for a NewRecord event"]
+    );
+}
+
+#[test]
+fn test_replay_changelist() {
+    use crate::domain::LevelInfo;
+    use crate::steamworks::{LeaderboardEntry, LeaderboardResponse};
+    use distance_util::LeaderboardGameMode;
+
+    let start = Utc::now();
+    let time = |minutes| start + chrono::Duration::minutes(minutes);
+    let snapshot = |steam_id, score, minutes| {
+        vec![LevelInfo {
+            name: "Broken Symmetry".into(),
+            mode: LeaderboardGameMode::Sprint,
+            leaderboard_name: "Broken Symmetry_1_stable".into(),
+            workshop_response: None,
+            leaderboard_response: LeaderboardResponse {
+                entries: Box::new([LeaderboardEntry {
+                    steam_id,
+                    global_rank: 1,
+                    score,
+                    player_name: Some(format!("Player {steam_id}")),
+                }]),
+                range_end: 2,
+            },
+            timestamp: time(minutes),
+            missing_since: None,
+        }]
+    };
+
+    let dir = tempfile::tempdir().unwrap();
+    let archive = SnapshotArchive::new(dir.path());
+    archive.save(&snapshot(1, 60_000, 0), time(0)).unwrap();
+    archive.save(&snapshot(2, 59_000, 5), time(5)).unwrap();
+    archive.save(&snapshot(3, 58_000, 10), time(10)).unwrap();
+    let times = archive.list().unwrap();
+    assert_eq!(times, [time(0), time(5), time(10)]);
+
+    let mut changelist = Vec::new();
+    let mut next = archive.load(time(5)).unwrap();
+    crate::update_changelist(
+        &mut changelist,
+        &mut next,
+        archive.load(time(0)).unwrap(),
+        None,
+        time(5),
+    );
+    assert_eq!(changelist.len(), 1);
+
+    // The rules changed after the first update, so it got a different event than a replay would
+    // produce, and the second update was missed entirely
+    changelist[0].kind = ChangelistEventKind::FirstRecord;
+    let (replayed, replaced) = replay_changelist(&changelist, &archive, &times, None).unwrap();
+    assert_eq!(replaced.len(), 1);
+    assert_eq!(
+        replayed
+            .iter()
+            .map(|x| (x.kind, x.new_record.as_ref().unwrap().steam_id))
+            .collect::<Vec<_>>(),
+        [
+            (ChangelistEventKind::NewRecord, 2),
+            (ChangelistEventKind::NewRecord, 3)
+        ]
+    );
+
+    // Replaying again from the same snapshots gives the same result
+    let (again, _) = replay_changelist(&[], &archive, &times, None).unwrap();
+    assert_eq!(
+        serde_json::to_value(&again).unwrap(),
+        serde_json::to_value(&replayed).unwrap()
+    );
+}
+
+#[test]
+fn test_replay_after_prune() {
+    use crate::domain::LevelInfo;
+    use crate::in_memory_persistence::InMemoryPersistence;
+    use crate::steamworks::{LeaderboardEntry, LeaderboardResponse};
+    use chrono::TimeZone;
+    use distance_util::LeaderboardGameMode;
+    use distance_wr_log_config::Snapshots as SnapshotsConfig;
+
+    let start = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+    let time = |minutes| start + chrono::Duration::minutes(minutes);
+    let snapshot = |steam_id, score, minutes| {
+        vec![LevelInfo {
+            name: "Broken Symmetry".into(),
+            mode: LeaderboardGameMode::Sprint,
+            leaderboard_name: "Broken Symmetry_1_stable".into(),
+            workshop_response: None,
+            leaderboard_response: LeaderboardResponse {
+                entries: Box::new([LeaderboardEntry {
+                    steam_id,
+                    global_rank: 1,
+                    score,
+                    player_name: Some(format!("Player {steam_id}")),
+                }]),
+                range_end: 2,
+            },
+            timestamp: time(minutes),
+            missing_since: None,
+        }]
+    };
+
+    let dir = tempfile::tempdir().unwrap();
+    let archive = SnapshotArchive::new(dir.path()).with_retention(SnapshotsConfig {
+        keep_all: Duration::ZERO,
+        keep_hourly: Duration::from_secs(30 * 24 * 60 * 60),
+        ..SnapshotsConfig::default()
+    });
+    let persistence = InMemoryPersistence::default();
+    let mut previous = snapshot(1, 60_000, 0);
+    archive.save(&previous, time(0)).unwrap();
+    for (steam_id, score, minutes) in [(2, 59_000, 5), (3, 58_000, 70)] {
+        let mut next = snapshot(steam_id, score, minutes);
+        archive.save(&next, time(minutes)).unwrap();
+        let mut changelist = persistence.load_changelist().unwrap_or_default();
+        let len = changelist.len();
+        crate::update_changelist(&mut changelist, &mut next, previous, None, time(minutes));
+        persistence
+            .append_changelist(&changelist[len..], &[])
+            .unwrap();
+        previous = next;
+    }
+
+    // Only the first snapshot of each hour is kept, so the update at 5 minutes is gone
+    assert_eq!(archive.prune(time(24 * 60)).unwrap(), 1);
+    let max_gap = Some(Duration::from_secs(10 * 60));
+    assert!(replay(&persistence, &archive, None, None, None, max_gap, false).is_err());
+    assert_eq!(persistence.load_changelist().unwrap().len(), 2);
+
+    // Allowing the gap merges both record changes into one
+    replay(&persistence, &archive, None, None, None, None, false).unwrap();
+    let changelist = persistence.load_changelist().unwrap();
+    assert_eq!(
+        changelist
+            .iter()
+            .map(|x| (
+                x.new_record.as_ref().unwrap().steam_id,
+                x.old_record.as_ref().unwrap().steam_id
+            ))
+            .collect::<Vec<_>>(),
+        [(3, 1)]
+    );
+}
@@ -0,0 +1,133 @@
+use crate::fixture_data_source::FixtureDataSource;
+use crate::steamworks::{LeaderboardResponse, Steamworks, WorkshopResponse};
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use distance_wr_log_config::Steam as SteamConfig;
+use futures::{future, Stream};
+use itertools::Either;
+use log::warn;
+
+/// Where the bot gets its leaderboard, workshop and player name data from.
+///
+/// [`Steamworks`](crate::steamworks::Steamworks) talks to the real Steam services, while
+/// [`FixtureDataSource`](crate::fixture_data_source::FixtureDataSource) serves canned data so
+/// update cycles can be run offline.
+pub trait DataSource {
+    /// Fetches the entries ranked `start..=end` on the given leaderboard.
+    async fn get_leaderboard_range(
+        &self,
+        leaderboard_name: &str,
+        start: i32,
+        end: i32,
+    ) -> Result<LeaderboardResponse>;
+
+    /// Streams every workshop level tagged with at least one of Sprint, Challenge, or Stunt.
+    fn get_all_workshop_sprint_challenge_stunt_levels(
+        &self,
+    ) -> impl Stream<Item = Result<WorkshopResponse>> + '_;
+
+    /// Streams the workshop levels tagged with at least one of Sprint, Challenge, or Stunt that
+    /// were published or updated at or after `since`. Levels updated earlier may be included too.
+    fn get_workshop_levels_updated_since(
+        &self,
+        since: DateTime<Utc>,
+    ) -> impl Stream<Item = Result<WorkshopResponse>> + '_;
+
+    /// Resolves the persona names of the given steam ids, in the same order. `None` means the name
+    /// couldn't be resolved.
+    async fn resolve_steam_names(
+        &self,
+        steam_ids: Vec<u64>,
+    ) -> Result<impl Iterator<Item = Option<String>>>;
+}
+
+/// The data source picked by the configuration: the fixture file if one is set, Steam otherwise.
+#[derive(Debug)]
+pub enum ConfiguredDataSource {
+    Steamworks(Steamworks),
+    Fixture(FixtureDataSource),
+}
+
+impl ConfiguredDataSource {
+    pub async fn new(config: &SteamConfig) -> Result<Self> {
+        if let Some(fixture_path) = &config.fixture_path {
+            warn!(
+                "Using fixture data from {} instead of Steam",
+                fixture_path.display()
+            );
+            return Ok(ConfiguredDataSource::Fixture(FixtureDataSource::load(
+                fixture_path,
+            )?));
+        }
+
+        let Some(grpc_address) = &config.grpc_server_address else {
+            bail!("`steam.grpc_server_address` (GRPC_SERVER_ADDRESS) is not set");
+        };
+        let Some(web_api_key) = &config.web_api_key else {
+            bail!("`steam.web_api_key` (STEAM_WEB_API_KEY) is not set");
+        };
+        let steamworks =
+            Steamworks::new(grpc_address.as_str(), web_api_key.as_str(), config.app_id).await?;
+
+        Ok(ConfiguredDataSource::Steamworks(steamworks))
+    }
+}
+
+impl DataSource for ConfiguredDataSource {
+    async fn get_leaderboard_range(
+        &self,
+        leaderboard_name: &str,
+        start: i32,
+        end: i32,
+    ) -> Result<LeaderboardResponse> {
+        match self {
+            ConfiguredDataSource::Steamworks(x) => {
+                x.get_leaderboard_range(leaderboard_name, start, end).await
+            }
+            ConfiguredDataSource::Fixture(x) => {
+                x.get_leaderboard_range(leaderboard_name, start, end).await
+            }
+        }
+    }
+
+    fn get_all_workshop_sprint_challenge_stunt_levels(
+        &self,
+    ) -> impl Stream<Item = Result<WorkshopResponse>> + '_ {
+        match self {
+            ConfiguredDataSource::Steamworks(x) => {
+                future::Either::Left(x.get_all_workshop_sprint_challenge_stunt_levels())
+            }
+            ConfiguredDataSource::Fixture(x) => {
+                future::Either::Right(x.get_all_workshop_sprint_challenge_stunt_levels())
+            }
+        }
+    }
+
+    fn get_workshop_levels_updated_since(
+        &self,
+        since: DateTime<Utc>,
+    ) -> impl Stream<Item = Result<WorkshopResponse>> + '_ {
+        match self {
+            ConfiguredDataSource::Steamworks(x) => {
+                future::Either::Left(x.get_workshop_levels_updated_since(since))
+            }
+            ConfiguredDataSource::Fixture(x) => {
+                future::Either::Right(x.get_workshop_levels_updated_since(since))
+            }
+        }
+    }
+
+    async fn resolve_steam_names(
+        &self,
+        steam_ids: Vec<u64>,
+    ) -> Result<impl Iterator<Item = Option<String>>> {
+        Ok(match self {
+            ConfiguredDataSource::Steamworks(x) => {
+                Either::Left(x.resolve_steam_names(steam_ids).await?)
+            }
+            ConfiguredDataSource::Fixture(x) => {
+                Either::Right(x.resolve_steam_names(steam_ids).await?)
+            }
+        })
+    }
+}
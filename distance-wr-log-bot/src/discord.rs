@@ -0,0 +1,241 @@
+use crate::domain::{self, ChangelistEvent, ChangelistEventKind};
+use anyhow::{bail, Context, Result};
+use distance_util::LeaderboardGameMode;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::File;
+use std::path::Path;
+
+const COLOR_NEW_RECORD: u32 = 0x2e_cc_71;
+const COLOR_RECORD_REMOVED: u32 = 0xe7_4c_3c;
+const COLOR_LEVEL: u32 = 0x34_98_db;
+const COLOR_RANK: u32 = 0xf1_c4_0f;
+
+/// The most embeds Discord accepts in one message.
+pub const MAX_EMBEDS_PER_MESSAGE: usize = 10;
+
+/// A Discord webhook that changelist events get posted to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiscordWebhook {
+    /// Identifies the webhook in the notification outbox, so the URL can be rotated.
+    pub name: String,
+    pub url: String,
+    /// Only post events for these modes. Empty means all modes.
+    #[serde(default)]
+    pub modes: Vec<LeaderboardGameMode>,
+    /// Only post events for these leaderboard names. Empty means all levels.
+    #[serde(default)]
+    pub levels: Vec<String>,
+    /// Only post these kinds of events. Empty means all kinds except the rank events, which have to
+    /// be listed to be posted.
+    #[serde(default)]
+    pub kinds: Vec<ChangelistEventKind>,
+}
+
+impl DiscordWebhook {
+    /// Loads the webhooks from a JSON file containing an array of them.
+    pub fn load_all(path: impl AsRef<Path>) -> Result<Vec<DiscordWebhook>> {
+        let path = path.as_ref();
+        let webhooks: Vec<DiscordWebhook> = serde_json::from_reader(
+            File::open(path).with_context(|| format!("couldn't open {}", path.display()))?,
+        )
+        .with_context(|| format!("couldn't parse {}", path.display()))?;
+
+        let mut names = HashSet::new();
+        for webhook in &webhooks {
+            if !names.insert(webhook.name.as_str()) {
+                bail!("duplicate Discord webhook name '{}'", webhook.name);
+            }
+        }
+
+        Ok(webhooks)
+    }
+
+    pub fn matches(&self, event: &ChangelistEvent) -> bool {
+        (self.modes.is_empty() || self.modes.contains(&event.mode))
+            && (self.levels.is_empty()
+                || event
+                    .leaderboard_name
+                    .as_ref()
+                    .is_some_and(|x| self.levels.contains(x)))
+            && if self.kinds.is_empty() {
+                !event.kind.is_rank_event()
+            } else {
+                self.kinds.contains(&event.kind)
+            }
+    }
+}
+
+/// Posts a message with an embed for each event to the webhook, which can be up to
+/// [`MAX_EMBEDS_PER_MESSAGE`] events.
+pub async fn post(
+    client: &reqwest::Client,
+    webhook: &DiscordWebhook,
+    events: &[&ChangelistEvent],
+) -> reqwest::Result<()> {
+    let embeds: Vec<_> = events.iter().map(|event| embed(event)).collect();
+    client
+        .post(&webhook.url)
+        .json(&serde_json::json!({ "embeds": embeds }))
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+/// Builds a Discord embed describing the event.
+pub fn embed(event: &ChangelistEvent) -> serde_json::Value {
+    let (title, color) = match event.kind {
+        ChangelistEventKind::NewRecord => (
+            format!("New record on {}", event.map_name),
+            COLOR_NEW_RECORD,
+        ),
+        ChangelistEventKind::FirstRecord => (
+            format!("First record on {}", event.map_name),
+            COLOR_NEW_RECORD,
+        ),
+        ChangelistEventKind::RecordRemoved => (
+            format!("Record removed on {}", event.map_name),
+            COLOR_RECORD_REMOVED,
+        ),
+        ChangelistEventKind::LevelPublished => {
+            (format!("{} was published", event.map_name), COLOR_LEVEL)
+        }
+        ChangelistEventKind::LevelRemoved => {
+            (format!("{} was removed", event.map_name), COLOR_LEVEL)
+        }
+        ChangelistEventKind::NewTopEntry => (
+            match event.rank {
+                Some(rank) => format!("New #{rank} on {}", event.map_name),
+                None => format!("New top entry on {}", event.map_name),
+            },
+            COLOR_RANK,
+        ),
+        ChangelistEventKind::PersonalBest => {
+            (format!("Personal best on {}", event.map_name), COLOR_RANK)
+        }
+    };
+    let format_score = |score| {
+        distance_util::format_score_legacy(score, event.mode).unwrap_or_else(|_| score.to_string())
+    };
+
+    let mut fields = vec![field("Mode", event.mode.to_string())];
+    if let Some(author) = &event.map_author {
+        fields.push(field("Author", author.clone()));
+    }
+    if event.kind.is_rank_event() {
+        // Both records belong to the same player
+        if let Some(new) = &event.new_record {
+            fields.push(field("Player", player_link(&new.player_name, new.steam_id)));
+            fields.push(field("Score", format_score(new.score)));
+        }
+        if let Some(rank) = event.rank {
+            fields.push(field("Rank", format!("#{rank}")));
+        }
+        if let Some(old) = &event.old_record {
+            fields.push(field("Previous score", format_score(old.score)));
+        }
+    } else {
+        if let Some(new) = &event.new_record {
+            fields.push(field(
+                "New holder",
+                player_link(&new.player_name, new.steam_id),
+            ));
+            fields.push(field("New score", format_score(new.score)));
+        }
+        if let Some(old) = &event.old_record {
+            fields.push(field(
+                "Old holder",
+                player_link(&old.player_name, old.steam_id),
+            ));
+            fields.push(field("Old score", format_score(old.score)));
+        }
+    }
+    if let (Some(new), Some(old)) = (&event.new_record, &event.old_record) {
+        fields.push(field(
+            "Improvement",
+            domain::format_improvement(new.score, old.score, event.mode),
+        ));
+    }
+
+    let mut embed = serde_json::json!({
+        "title": title,
+        "color": color,
+        "fields": fields,
+        "timestamp": event.fetch_time.to_rfc3339(),
+    });
+    if let Some(id) = event.workshop_item_id {
+        embed["url"] =
+            format!("https://steamcommunity.com/sharedfiles/filedetails/?id={id}").into();
+    }
+    if let Some(preview) = event.map_preview.as_ref().filter(|x| !x.is_empty()) {
+        embed["image"] = serde_json::json!({ "url": preview });
+    }
+
+    embed
+}
+
+fn field(name: &str, value: String) -> serde_json::Value {
+    serde_json::json!({ "name": name, "value": value, "inline": true })
+}
+
+fn player_link(player_name: &str, steam_id: u64) -> String {
+    format!("[{player_name}](https://steamcommunity.com/profiles/{steam_id})")
+}
+
+#[test]
+fn test_discord_embed() {
+    use crate::domain::ChangelistRecord;
+    use chrono::Utc;
+
+    let event = ChangelistEvent {
+        kind: ChangelistEventKind::NewRecord,
+        leaderboard_name: Some("level_1_stable".into()),
+        map_name: "Level".into(),
+        mode: LeaderboardGameMode::Sprint,
+        map_author: None,
+        map_preview: Some("https://example.com/preview.png".into()),
+        workshop_item_id: Some(100),
+        steam_id_author: None,
+        new_record: Some(ChangelistRecord {
+            steam_id: 2,
+            player_name: "Bob".into(),
+            score: 61_250,
+        }),
+        old_record: Some(ChangelistRecord {
+            steam_id: 1,
+            player_name: "Alice".into(),
+            score: 62_000,
+        }),
+        rank: None,
+        fetch_time: Utc::now(),
+    };
+
+    let embed = embed(&event);
+    assert_eq!(embed["title"], "New record on Level");
+    assert_eq!(
+        embed["url"],
+        "https://steamcommunity.com/sharedfiles/filedetails/?id=100"
+    );
+    assert_eq!(embed["image"]["url"], "https://example.com/preview.png");
+    let improvement = embed["fields"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|x| x["name"] == "Improvement")
+        .unwrap();
+    assert_eq!(improvement["value"], "0.750 s");
+
+    let webhook = |modes, levels: &[&str]| DiscordWebhook {
+        name: "test".into(),
+        url: String::new(),
+        modes,
+        levels: levels.iter().map(|x| x.to_string()).collect(),
+        kinds: Vec::new(),
+    };
+    assert!(webhook(Vec::new(), &[]).matches(&event));
+    assert!(webhook(vec![LeaderboardGameMode::Sprint], &["level_1_stable"]).matches(&event));
+    assert!(!webhook(vec![LeaderboardGameMode::Stunt], &[]).matches(&event));
+    assert!(!webhook(Vec::new(), &["level_2_stable"]).matches(&event));
+}
@@ -2,6 +2,8 @@ use crate::steamworks::{LeaderboardResponse, WorkshopResponse};
 use chrono::{DateTime, Utc};
 use distance_util::LeaderboardGameMode;
 use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LevelInfo {
@@ -11,9 +13,240 @@ pub struct LevelInfo {
     pub workshop_response: Option<WorkshopResponse>,
     pub leaderboard_response: LeaderboardResponse,
     pub timestamp: DateTime<Utc>,
+
+    /// When the level was first found missing from the workshop, if it currently is. Such levels
+    /// are kept around for a grace period before they're considered removed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub missing_since: Option<DateTime<Utc>>,
+}
+
+/// What the previous updates fetched, so the next one knows what it can skip.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FetchState {
+    /// When the last full sweep of the workshop started.
+    pub last_full_sweep: Option<DateTime<Utc>>,
+    /// When the last complete listing of the workshop, full or incremental, started.
+    pub workshop_listed_at: Option<DateTime<Utc>>,
+    /// The leaderboards whose last poll failed, by leaderboard name. They're retried on every
+    /// update until they succeed.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub failures: BTreeMap<String, FetchFailure>,
+    /// Since when the leaderboards that had entries have been coming back empty, by leaderboard
+    /// name. Their old entries are kept until the level removal grace period is over.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub emptied: BTreeMap<String, DateTime<Utc>>,
+}
+
+/// A leaderboard that couldn't be polled, with what's needed to retry it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FetchFailure {
+    pub name: String,
+    pub mode: LeaderboardGameMode,
+    pub workshop_response: Option<WorkshopResponse>,
+    pub kind: FetchFailureKind,
+    /// When the first of the consecutive failed polls happened.
+    pub since: DateTime<Utc>,
+    pub attempts: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FetchFailureKind {
+    /// Steam returned an error.
+    Error { message: String },
+    /// The request took longer than the fetch timeout.
+    Timeout,
+    /// The time budget ran out before the leaderboard was polled.
+    Skipped,
+}
+
+impl Display for FetchFailureKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchFailureKind::Error { message } => write!(f, "error: {message}"),
+            FetchFailureKind::Timeout => f.write_str("timed out"),
+            FetchFailureKind::Skipped => f.write_str("skipped"),
+        }
+    }
+}
+
+/// The version of the changelist format written by [`ChangelistFile`].
+pub const CHANGELIST_VERSION: u32 = 2;
+
+/// The versioned changelist document.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangelistFile {
+    pub version: u32,
+    pub events: Vec<ChangelistEvent>,
+    /// Notifications not delivered yet, stored alongside the events so both are saved together.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outbox: Vec<Notification>,
+}
+
+impl Default for ChangelistFile {
+    fn default() -> Self {
+        ChangelistFile {
+            version: CHANGELIST_VERSION,
+            events: Vec::new(),
+            outbox: Vec::new(),
+        }
+    }
+}
+
+/// A changelist event waiting to be delivered to a notification target.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notification {
+    /// Identifies the delivery of this event to this target, so it's never enqueued twice.
+    pub id: String,
+    /// The name of the target, e.g. a Discord webhook.
+    pub target: String,
+    pub event: ChangelistEvent,
+    pub created_at: DateTime<Utc>,
+}
+
+/// An entry in the changelist.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangelistEvent {
+    pub kind: ChangelistEventKind,
+
+    /// `None` for events imported from the legacy format whose leaderboard couldn't be determined.
+    pub leaderboard_name: Option<String>,
+    pub map_name: String,
+    pub mode: LeaderboardGameMode,
+    pub map_author: Option<String>,
+    pub map_preview: Option<String>,
+    pub workshop_item_id: Option<u64>,
+    pub steam_id_author: Option<u64>,
+    pub new_record: Option<ChangelistRecord>,
+    pub old_record: Option<ChangelistRecord>,
+    /// The rank of `new_record`, for rank events.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rank: Option<i32>,
+    pub fetch_time: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangelistRecord {
+    pub steam_id: u64,
+    pub player_name: String,
+    pub score: i32,
+}
+
+/// What a [`ChangelistEvent`] describes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangelistEventKind {
+    /// A new #1 that beats the previous one. `old_record` is the record that was beaten, if there
+    /// was one.
+    #[default]
+    NewRecord,
+
+    /// The #1 was removed or the leaderboard was rolled back, e.g. when a cheated score gets wiped.
+    /// `old_record` is the removed record, and `new_record` the #1 that took its place, if any.
+    RecordRemoved,
+
+    /// The first record ever seen on a leaderboard. Only `new_record` is set.
+    FirstRecord,
+
+    /// A level appeared on the workshop. Neither record is set.
+    LevelPublished,
+
+    /// A level disappeared from the workshop. `old_record` is the last record seen on it, if there
+    /// was one.
+    LevelRemoved,
+
+    /// A player below the #1 moved into the top ranks with a new score. `new_record` is their
+    /// entry, and `old_record` their previous entry if it was in the fetched range.
+    NewTopEntry,
+
+    /// A player below the #1 who was already in the fetched range improved their score.
+    /// `new_record` and `old_record` are their new and previous entries.
+    PersonalBest,
+}
+
+impl ChangelistEventKind {
+    /// Rank events are about the ranks below the #1. They're only created when enabled, and left
+    /// out of the WR log unless asked for.
+    pub fn is_rank_event(self) -> bool {
+        matches!(
+            self,
+            ChangelistEventKind::NewTopEntry | ChangelistEventKind::PersonalBest
+        )
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl ChangelistEvent {
+    /// Whether the events are about the same thing: the same workshop level for level events,
+    /// and the same leaderboard for the others. Rank events are only about the same thing as other
+    /// rank events.
+    pub fn has_same_subject_as(&self, other: &Self) -> bool {
+        let is_level_event = |kind: ChangelistEventKind| {
+            matches!(
+                kind,
+                ChangelistEventKind::LevelPublished | ChangelistEventKind::LevelRemoved
+            )
+        };
+        if is_level_event(self.kind) != is_level_event(other.kind)
+            || self.kind.is_rank_event() != other.kind.is_rank_event()
+        {
+            return false;
+        }
+        if is_level_event(self.kind) && self.workshop_item_id.is_some() {
+            return self.workshop_item_id == other.workshop_item_id;
+        }
+
+        match (&self.leaderboard_name, &other.leaderboard_name) {
+            (Some(a), Some(b)) => a == b,
+            _ => {
+                self.map_name == other.map_name
+                    && self.mode == other.mode
+                    && self.workshop_item_id == other.workshop_item_id
+            }
+        }
+    }
+
+    pub fn is_likely_a_duplicate_of(&self, other: &Self) -> bool {
+        self.kind == other.kind
+            && self.map_name == other.map_name
+            && self.mode == other.mode
+            && self.workshop_item_id == other.workshop_item_id
+            && self.steam_id_author == other.steam_id_author
+            && self.new_record.as_ref().map(|x| (x.steam_id, x.score))
+                == other.new_record.as_ref().map(|x| (x.steam_id, x.score))
+    }
+
+    /// Converts the event to the legacy format read by the frontend, which only knows about new
+    /// records. Other kinds of events have no legacy representation.
+    pub fn to_legacy(&self) -> Option<ChangelistEntry> {
+        if !matches!(
+            self.kind,
+            ChangelistEventKind::NewRecord | ChangelistEventKind::FirstRecord
+        ) {
+            return None;
+        }
+        let new_record = self.new_record.as_ref()?;
+        let format_score = |score| distance_util::format_score_legacy(score, self.mode).unwrap();
+
+        Some(ChangelistEntry {
+            map_name: self.map_name.clone(),
+            map_author: self.map_author.clone(),
+            map_preview: self.map_preview.clone(),
+            mode: format!("{}", self.mode),
+            new_recordholder: new_record.player_name.clone(),
+            old_recordholder: self.old_record.as_ref().map(|x| x.player_name.clone()),
+            record_new: format_score(new_record.score),
+            record_old: self.old_record.as_ref().map(|x| format_score(x.score)),
+            workshop_item_id: self.workshop_item_id.map(|x| format!("{x}")),
+            steam_id_author: self.steam_id_author.map(|x| format!("{x}")),
+            steam_id_new_recordholder: format!("{}", new_record.steam_id),
+            steam_id_old_recordholder: self.old_record.as_ref().map(|x| format!("{}", x.steam_id)),
+            fetch_time: self.fetch_time.to_rfc2822(),
+        })
+    }
+}
+
+/// A changelist entry in the legacy format read by the frontend.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChangelistEntry {
     pub map_name: String,
     pub map_author: Option<String>,
@@ -30,13 +263,60 @@ pub struct ChangelistEntry {
     pub fetch_time: String,
 }
 
-impl ChangelistEntry {
-    pub fn is_likely_a_duplicate_of(&self, other: &Self) -> bool {
-        self.map_name == other.map_name
-            && self.mode == other.mode
-            && self.record_new == other.record_new
-            && self.workshop_item_id == other.workshop_item_id
-            && self.steam_id_author == other.steam_id_author
-            && self.steam_id_new_recordholder == other.steam_id_new_recordholder
+/// Formats how much better `new` is than `old`. Negative when the record got worse, e.g. after a
+/// removal.
+pub fn format_improvement(new: i32, old: i32, mode: LeaderboardGameMode) -> String {
+    match mode {
+        LeaderboardGameMode::Sprint | LeaderboardGameMode::Challenge => {
+            let milliseconds = i64::from(old) - i64::from(new);
+            format!("{:.3} s", milliseconds as f64 / 1000.0)
+        }
+        LeaderboardGameMode::Stunt => {
+            format!("{} eV", i64::from(new) - i64::from(old))
+        }
     }
 }
+
+/// A stable identifier for the event, derived from the leaderboard and the new record.
+pub fn guid(event: &ChangelistEvent) -> String {
+    let leaderboard = event
+        .leaderboard_name
+        .clone()
+        .unwrap_or_else(|| format!("{}_{}", event.map_name, event.mode));
+    let kind = serde_json::to_value(event.kind).unwrap();
+    let kind = kind.as_str().unwrap();
+
+    match &event.new_record {
+        Some(record) => format!(
+            "urn:distance-wr-log:{kind}:{}:{}:{}",
+            escape(&leaderboard),
+            record.steam_id,
+            record.score
+        ),
+        None => format!(
+            "urn:distance-wr-log:{kind}:{}:{}",
+            escape(&leaderboard),
+            event.fetch_time.timestamp()
+        ),
+    }
+}
+
+/// Percent-encodes everything but ASCII letters, digits, `-`, `.` and `_`.
+fn escape(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+/// Converts a changelist to the legacy format.
+pub fn legacy_changelist(events: &[ChangelistEvent]) -> Vec<ChangelistEntry> {
+    events
+        .iter()
+        .filter_map(ChangelistEvent::to_legacy)
+        .collect()
+}
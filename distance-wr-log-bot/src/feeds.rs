@@ -0,0 +1,318 @@
+use crate::domain::{self, ChangelistEvent, ChangelistEventKind};
+use crate::file_json_persistence;
+use anyhow::{Context, Result};
+use atom_syndication::{EntryBuilder, FeedBuilder, LinkBuilder, Text};
+use chrono::{DateTime, Utc};
+use distance_util::LeaderboardGameMode;
+use rss::{ChannelBuilder, GuidBuilder, ItemBuilder};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How many of the latest events each feed contains.
+const MAX_FEED_ENTRIES: usize = 100;
+
+const FEED_TITLE: &str = "Distance WR Log";
+
+/// Where feeds are written, and in which formats.
+#[derive(Debug, Clone)]
+pub struct FeedConfig {
+    /// The main feed goes here, and the per-mode and per-player feeds into a `feeds` subdirectory.
+    pub dir: PathBuf,
+    /// Also write RSS 2.0 feeds next to the Atom ones.
+    pub rss: bool,
+    /// Where the feeds link to, normally the frontend.
+    pub link: String,
+}
+
+/// A feed over a subset of the changelist.
+#[derive(Debug)]
+struct Feed<'a> {
+    /// The file name, without extension, relative to the feed directory.
+    path: PathBuf,
+    title: String,
+    events: Vec<&'a ChangelistEvent>,
+}
+
+/// Writes the main feed, a feed per mode, and a feed per player.
+///
+/// Player feeds are only rewritten if the player appears in `new_events`, or their feed doesn't
+/// exist yet, since there can be a lot of them.
+pub fn write_feeds(
+    config: &FeedConfig,
+    changelist: &[ChangelistEvent],
+    new_events: &[ChangelistEvent],
+) -> Result<()> {
+    let mut feeds = vec![Feed {
+        path: PathBuf::from("changelist"),
+        title: FEED_TITLE.to_owned(),
+        events: latest(changelist.iter()),
+    }];
+
+    for mode in [
+        LeaderboardGameMode::Sprint,
+        LeaderboardGameMode::Challenge,
+        LeaderboardGameMode::Stunt,
+    ] {
+        feeds.push(Feed {
+            path: Path::new("feeds").join(mode.name().to_lowercase()),
+            title: format!("{FEED_TITLE}: {mode}"),
+            events: latest(changelist.iter().filter(|event| event.mode == mode)),
+        });
+    }
+
+    let is_record_event = |event: &&ChangelistEvent| !event.kind.is_rank_event();
+    let changed_players: BTreeSet<u64> = new_events
+        .iter()
+        .filter(is_record_event)
+        .flat_map(player_ids)
+        .collect();
+    let players: BTreeSet<u64> = changelist
+        .iter()
+        .filter(is_record_event)
+        .flat_map(player_ids)
+        .collect();
+    for steam_id in players {
+        let path = Path::new("feeds")
+            .join("players")
+            .join(steam_id.to_string());
+        if !changed_players.contains(&steam_id)
+            && config.dir.join(&path).with_extension("atom").exists()
+        {
+            continue;
+        }
+
+        let events = latest(
+            changelist
+                .iter()
+                .filter(|event| player_ids(event).any(|x| x == steam_id)),
+        );
+        let player_name = events
+            .iter()
+            .flat_map(|event| [&event.new_record, &event.old_record])
+            .flatten()
+            .find(|record| record.steam_id == steam_id)
+            .map_or_else(|| steam_id.to_string(), |record| record.player_name.clone());
+        feeds.push(Feed {
+            path,
+            title: format!("{FEED_TITLE}: {player_name}"),
+            events,
+        });
+    }
+
+    for feed in &feeds {
+        let path = config.dir.join(&feed.path);
+        fs::create_dir_all(path.parent().unwrap())?;
+
+        file_json_persistence::write_atomically(
+            atom_feed(feed, &config.link).to_string().as_bytes(),
+            &path.with_extension("atom"),
+        )
+        .with_context(|| format!("error writing the {} feed", feed.path.display()))?;
+        if config.rss {
+            file_json_persistence::write_atomically(
+                rss_feed(feed, &config.link).to_string().as_bytes(),
+                &path.with_extension("rss"),
+            )
+            .with_context(|| format!("error writing the {} feed", feed.path.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The latest events, newest first. Rank events are left out, so the feeds only follow the #1s.
+fn latest<'a>(
+    events: impl DoubleEndedIterator<Item = &'a ChangelistEvent>,
+) -> Vec<&'a ChangelistEvent> {
+    events
+        .filter(|event| !event.kind.is_rank_event())
+        .rev()
+        .take(MAX_FEED_ENTRIES)
+        .collect()
+}
+
+fn player_ids(event: &ChangelistEvent) -> impl Iterator<Item = u64> + '_ {
+    [&event.new_record, &event.old_record]
+        .into_iter()
+        .flatten()
+        .map(|record| record.steam_id)
+}
+
+fn atom_feed(feed: &Feed, link: &str) -> atom_syndication::Feed {
+    let entries = feed
+        .events
+        .iter()
+        .map(|event| {
+            let mut entry = EntryBuilder::default();
+            entry
+                .id(domain::guid(event))
+                .title(title(event))
+                .summary(Some(Text::plain(description(event))))
+                .updated(event.fetch_time)
+                .published(Some(event.fetch_time.into()));
+            if let Some(url) = workshop_url(event) {
+                entry.links(vec![LinkBuilder::default().href(url).build()]);
+            }
+            entry.build()
+        })
+        .collect::<Vec<_>>();
+    let updated: DateTime<Utc> = feed
+        .events
+        .first()
+        .map_or(DateTime::UNIX_EPOCH, |event| event.fetch_time);
+
+    FeedBuilder::default()
+        .id(format!("urn:distance-wr-log:feed:{}", feed.path.display()))
+        .title(feed.title.as_str())
+        .links(vec![LinkBuilder::default().href(link).build()])
+        .updated(updated)
+        .entries(entries)
+        .build()
+}
+
+fn rss_feed(feed: &Feed, link: &str) -> rss::Channel {
+    let items = feed
+        .events
+        .iter()
+        .map(|event| {
+            ItemBuilder::default()
+                .guid(Some(
+                    GuidBuilder::default()
+                        .value(domain::guid(event))
+                        .permalink(false)
+                        .build(),
+                ))
+                .title(Some(title(event)))
+                .description(Some(description(event)))
+                .link(workshop_url(event))
+                .pub_date(Some(event.fetch_time.to_rfc2822()))
+                .build()
+        })
+        .collect::<Vec<_>>();
+
+    ChannelBuilder::default()
+        .title(feed.title.as_str())
+        .link(link)
+        .description(feed.title.as_str())
+        .items(items)
+        .build()
+}
+
+fn title(event: &ChangelistEvent) -> String {
+    let map = format!("{} ({})", event.map_name, event.mode);
+    match (event.kind, &event.new_record) {
+        (ChangelistEventKind::NewRecord, Some(new)) => {
+            format!("{} set a new record on {map}", new.player_name)
+        }
+        (ChangelistEventKind::FirstRecord, Some(new)) => {
+            format!("{} set the first record on {map}", new.player_name)
+        }
+        (ChangelistEventKind::NewTopEntry, Some(new)) => match event.rank {
+            Some(rank) => format!("{} is now #{rank} on {map}", new.player_name),
+            None => format!("{} moved into the top ranks on {map}", new.player_name),
+        },
+        (ChangelistEventKind::PersonalBest, Some(new)) => {
+            format!("{} improved their score on {map}", new.player_name)
+        }
+        (ChangelistEventKind::RecordRemoved, _) => format!("The record on {map} was removed"),
+        (ChangelistEventKind::LevelPublished, _) => format!("{map} was published"),
+        (ChangelistEventKind::LevelRemoved, _) | (_, None) => format!("{map} was removed"),
+    }
+}
+
+fn description(event: &ChangelistEvent) -> String {
+    let format_score = |score| {
+        distance_util::format_score_legacy(score, event.mode).unwrap_or_else(|_| score.to_string())
+    };
+
+    let mut lines = Vec::new();
+    if let Some(author) = &event.map_author {
+        lines.push(format!("Level by {author}."));
+    }
+    if let Some(new) = &event.new_record {
+        lines.push(format!(
+            "Record: {} by {}.",
+            format_score(new.score),
+            new.player_name
+        ));
+    }
+    if let Some(old) = &event.old_record {
+        lines.push(format!(
+            "Previous record: {} by {}.",
+            format_score(old.score),
+            old.player_name
+        ));
+    }
+    if let (Some(new), Some(old)) = (&event.new_record, &event.old_record) {
+        lines.push(format!(
+            "Improvement: {}.",
+            domain::format_improvement(new.score, old.score, event.mode)
+        ));
+    }
+
+    lines.join(" ")
+}
+
+fn workshop_url(event: &ChangelistEvent) -> Option<String> {
+    event
+        .workshop_item_id
+        .map(|id| format!("https://steamcommunity.com/sharedfiles/filedetails/?id={id}"))
+}
+
+#[test]
+fn test_write_feeds() {
+    use crate::domain::ChangelistRecord;
+
+    let event = |steam_id, score| ChangelistEvent {
+        kind: ChangelistEventKind::NewRecord,
+        leaderboard_name: Some("My Level_1_100".into()),
+        map_name: "My Level".into(),
+        mode: LeaderboardGameMode::Sprint,
+        map_author: Some("Author".into()),
+        map_preview: None,
+        workshop_item_id: Some(100),
+        steam_id_author: Some(1),
+        new_record: Some(ChangelistRecord {
+            steam_id,
+            player_name: format!("Player {steam_id}"),
+            score,
+        }),
+        old_record: None,
+        rank: None,
+        fetch_time: Utc::now(),
+    };
+    let changelist = [event(2, 60_000), event(3, 59_000)];
+    assert_eq!(
+        domain::guid(&changelist[0]),
+        "urn:distance-wr-log:new_record:My%20Level_1_100:2:60000"
+    );
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = FeedConfig {
+        dir: dir.path().to_owned(),
+        rss: true,
+        link: "https://example.com/".into(),
+    };
+    write_feeds(&config, &changelist, &changelist[1..]).unwrap();
+
+    let feed: atom_syndication::Feed = fs::read_to_string(dir.path().join("changelist.atom"))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert_eq!(
+        feed.entries().iter().map(|x| x.id()).collect::<Vec<_>>(),
+        [domain::guid(&changelist[1]), domain::guid(&changelist[0])]
+    );
+    let feed: rss::Channel = fs::read_to_string(dir.path().join("feeds/sprint.rss"))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert_eq!(feed.items().len(), 2);
+    let feed: atom_syndication::Feed = fs::read_to_string(dir.path().join("feeds/players/3.atom"))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert_eq!(feed.entries().len(), 1);
+    assert!(dir.path().join("feeds/players/2.atom").exists());
+}
@@ -1,45 +1,191 @@
-use crate::{ChangelistEntry, LevelInfo};
-use anyhow::{Context, Result};
+use crate::domain::{
+    self, ChangelistEvent, ChangelistFile, FetchState, LevelInfo, Notification, CHANGELIST_VERSION,
+};
+use crate::name_cache::NameCache;
+use crate::name_history::NameHistory;
+use crate::persistence::{LoadError, Persistence};
+use crate::record_history::RecordHistory;
+use anyhow::{anyhow, Context, Result};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use std::fs::File;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
 use std::io;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use tempfile::NamedTempFile;
-use thiserror::Error;
 
+/// Stores everything as JSON files in a single directory.
+///
+/// Besides the versioned `changelist_v2.json`, the changelist is also written to `changelist.json`
+/// in the legacy format read by the frontend.
 #[derive(Debug, Clone)]
 pub struct FileJsonPersistence {
     query_results_path: PathBuf,
     changelist_path: PathBuf,
+    legacy_changelist_path: PathBuf,
+    record_history_path: PathBuf,
+    fetch_state_path: PathBuf,
+    name_cache_path: PathBuf,
+    name_history_path: PathBuf,
 }
 
 impl FileJsonPersistence {
-    pub fn new(
-        query_results_path: impl Into<PathBuf>,
-        changelist_path: impl Into<PathBuf>,
-    ) -> Self {
+    pub fn new(data_dir: impl AsRef<Path>) -> Self {
+        let data_dir = data_dir.as_ref();
+
         FileJsonPersistence {
-            query_results_path: query_results_path.into(),
-            changelist_path: changelist_path.into(),
+            query_results_path: data_dir.join("query_results.json"),
+            changelist_path: data_dir.join("changelist_v2.json"),
+            legacy_changelist_path: data_dir.join("changelist.json"),
+            record_history_path: data_dir.join("record_history.json"),
+            fetch_state_path: data_dir.join("fetch_state.json"),
+            name_cache_path: data_dir.join("name_cache.json"),
+            name_history_path: data_dir.join("name_history.json"),
+        }
+    }
+}
+
+impl FileJsonPersistence {
+    fn load_changelist_file(&self) -> Result<ChangelistFile, LoadError> {
+        match load_file::<ChangelistFile>(&self.changelist_path) {
+            Ok(file) if file.version == CHANGELIST_VERSION => Ok(file),
+            Ok(file) => Err(LoadError::Other(anyhow!(
+                "unsupported changelist version {}",
+                file.version
+            ))),
+            Err(LoadError::DoesNotExist) if self.legacy_changelist_path.exists() => {
+                // Starting a new changelist would overwrite the legacy one
+                Err(LoadError::NeedsMigration)
+            }
+            Err(e) => Err(e),
         }
     }
 
-    pub fn load_query_results(&self) -> Result<Vec<LevelInfo>, LoadError> {
+    fn save_changelist_file(&self, file: &ChangelistFile) -> Result<()> {
+        save_json::<_, ChangelistFile>(file, &self.changelist_path)?;
+        save_file(
+            &domain::legacy_changelist(&file.events),
+            &self.legacy_changelist_path,
+        )
+    }
+}
+
+impl Persistence for FileJsonPersistence {
+    fn load_query_results(&self) -> Result<Vec<LevelInfo>, LoadError> {
         load_file(&self.query_results_path)
     }
 
-    pub fn save_query_results(&self, query_results: &[LevelInfo]) -> Result<()> {
+    fn save_query_results(&self, query_results: &[LevelInfo]) -> Result<()> {
         save_file(query_results, &self.query_results_path)
     }
 
-    pub fn load_changelist(&self) -> Result<Vec<ChangelistEntry>, LoadError> {
-        load_file(&self.changelist_path)
+    fn load_changelist(&self) -> Result<Vec<ChangelistEvent>, LoadError> {
+        Ok(self.load_changelist_file()?.events)
+    }
+
+    fn append_changelist(
+        &self,
+        events: &[ChangelistEvent],
+        notifications: &[Notification],
+    ) -> Result<()> {
+        let mut file = match self.load_changelist_file() {
+            Ok(x) => x,
+            Err(LoadError::DoesNotExist) => ChangelistFile::default(),
+            Err(e) => return Err(e.into()),
+        };
+        file.events.extend_from_slice(events);
+        for notification in notifications {
+            if file.outbox.iter().all(|x| x.id != notification.id) {
+                file.outbox.push(notification.clone());
+            }
+        }
+
+        self.save_changelist_file(&file)
+    }
+
+    fn replace_changelist(&self, events: &[ChangelistEvent]) -> Result<()> {
+        let mut file = match self.load_changelist_file() {
+            Ok(x) => x,
+            Err(LoadError::DoesNotExist | LoadError::NeedsMigration) => ChangelistFile::default(),
+            Err(e) => return Err(e.into()),
+        };
+        file.events = events.to_vec();
+
+        self.save_changelist_file(&file)
+    }
+
+    fn load_outbox(&self) -> Result<Vec<Notification>> {
+        match self.load_changelist_file() {
+            Ok(file) => Ok(file.outbox),
+            Err(LoadError::DoesNotExist | LoadError::NeedsMigration) => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn remove_from_outbox(&self, ids: &[String]) -> Result<()> {
+        let mut file = self.load_changelist_file()?;
+        file.outbox
+            .retain(|notification| !ids.contains(&notification.id));
+
+        // The legacy export doesn't include the outbox, so it doesn't need updating
+        save_json::<_, ChangelistFile>(&file, &self.changelist_path)
+    }
+
+    fn load_record_history(&self) -> Result<RecordHistory, LoadError> {
+        load_file(&self.record_history_path)
     }
 
-    pub fn save_changelist(&self, changelist: &[ChangelistEntry]) -> Result<()> {
-        save_file(changelist, &self.changelist_path)
+    fn save_record_history(&self, record_history: &RecordHistory) -> Result<()> {
+        save_json::<_, RecordHistory>(record_history, &self.record_history_path)
+    }
+
+    fn load_fetch_state(&self) -> Result<FetchState, LoadError> {
+        load_file(&self.fetch_state_path)
+    }
+
+    fn save_fetch_state(&self, fetch_state: &FetchState) -> Result<()> {
+        save_json::<_, FetchState>(fetch_state, &self.fetch_state_path)
+    }
+
+    fn load_name_cache(&self) -> Result<NameCache, LoadError> {
+        load_file(&self.name_cache_path)
+    }
+
+    fn save_name_cache(&self, name_cache: &NameCache) -> Result<()> {
+        save_json::<_, NameCache>(name_cache, &self.name_cache_path)
+    }
+
+    fn load_name_history(&self) -> Result<NameHistory, LoadError> {
+        load_file(&self.name_history_path)
+    }
+
+    fn save_name_history(&self, name_history: &NameHistory) -> Result<()> {
+        save_json::<_, NameHistory>(name_history, &self.name_history_path)
+    }
+
+    /// Every file is replaced on save, so their modification times tell whether anything changed.
+    fn data_version(&self) -> Result<u64> {
+        let mut hasher = DefaultHasher::new();
+        for path in [
+            &self.query_results_path,
+            &self.changelist_path,
+            &self.legacy_changelist_path,
+            &self.record_history_path,
+            &self.fetch_state_path,
+            &self.name_cache_path,
+            &self.name_history_path,
+        ] {
+            let modified = match fs::metadata(path) {
+                Ok(metadata) => Some(metadata.modified()?),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            };
+            modified.hash(&mut hasher);
+        }
+
+        Ok(hasher.finish())
     }
 }
 
@@ -61,16 +207,30 @@ where
     }
 }
 
-fn save_file<T: Serialize + DeserializeOwned>(data: &[T], path: &Path) -> Result<()> {
-    let serialized = serde_json::to_vec(&data)?;
+pub fn save_file<T: Serialize + DeserializeOwned>(data: &[T], path: &Path) -> Result<()> {
+    save_json::<_, Vec<T>>(data, path)
+}
+
+/// Atomically writes `data` to `path` as JSON, after making sure it deserializes back as a `D`.
+fn save_json<T, D>(data: &T, path: &Path) -> Result<()>
+where
+    T: Serialize + ?Sized,
+    D: DeserializeOwned,
+{
+    let serialized = serde_json::to_vec(data)?;
 
     // Make sure the JSON we just generated is valid
-    let _: Vec<T> =
+    let _: D =
         serde_json::from_slice(&serialized).context("the JSON we just generated is not valid")?;
 
+    write_atomically(&serialized, path)
+}
+
+/// Replaces the contents of `path` with `data`, so readers never see a partially written file.
+pub fn write_atomically(data: &[u8], path: &Path) -> Result<()> {
     // Atomically update the file using a temporary file
     let mut tmp = NamedTempFile::new_in(path.parent().unwrap())?;
-    tmp.write_all(&serialized)?;
+    tmp.write_all(data)?;
     #[allow(unused_variables)]
     let file = tmp.persist(path)?;
 
@@ -91,12 +251,3 @@ fn save_file<T: Serialize + DeserializeOwned>(data: &[T], path: &Path) -> Result
 
     Ok(())
 }
-
-#[derive(Error, Debug)]
-pub enum LoadError {
-    #[error("The requested item does not exist.")]
-    DoesNotExist,
-
-    #[error("{0}")]
-    Other(#[from] anyhow::Error),
-}
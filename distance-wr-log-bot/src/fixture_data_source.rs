@@ -0,0 +1,85 @@
+use crate::data_source::DataSource;
+use crate::steamworks::{LeaderboardResponse, WorkshopResponse};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use futures::{stream, Stream};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+
+/// A [`DataSource`] backed by in-memory data, typically loaded from a JSON fixture file.
+///
+/// Leaderboards that aren't present in the fixture are treated as empty, and steam ids without a
+/// known name resolve to `None`, just like they can with the real Steam services.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FixtureDataSource {
+    pub leaderboards: HashMap<String, LeaderboardResponse>,
+    pub workshop_levels: Vec<WorkshopResponse>,
+    pub steam_names: HashMap<u64, String>,
+}
+
+impl FixtureDataSource {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .with_context(|| format!("couldn't open fixture file {}", path.display()))?;
+
+        serde_json::from_reader(file)
+            .with_context(|| format!("couldn't parse fixture file {}", path.display()))
+    }
+}
+
+impl DataSource for FixtureDataSource {
+    async fn get_leaderboard_range(
+        &self,
+        leaderboard_name: &str,
+        start: i32,
+        end: i32,
+    ) -> Result<LeaderboardResponse> {
+        let entries = self
+            .leaderboards
+            .get(leaderboard_name)
+            .map(|response| {
+                response
+                    .entries
+                    .iter()
+                    .filter(|entry| (start..=end).contains(&entry.global_rank))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(LeaderboardResponse {
+            entries,
+            range_end: end,
+        })
+    }
+
+    fn get_all_workshop_sprint_challenge_stunt_levels(
+        &self,
+    ) -> impl Stream<Item = Result<WorkshopResponse>> + '_ {
+        stream::iter(self.workshop_levels.iter().cloned().map(Ok))
+    }
+
+    /// Fixtures don't record when levels were updated, so every level counts as updated.
+    fn get_workshop_levels_updated_since(
+        &self,
+        _since: DateTime<Utc>,
+    ) -> impl Stream<Item = Result<WorkshopResponse>> + '_ {
+        self.get_all_workshop_sprint_challenge_stunt_levels()
+    }
+
+    async fn resolve_steam_names(
+        &self,
+        steam_ids: Vec<u64>,
+    ) -> Result<impl Iterator<Item = Option<String>>> {
+        let names: Vec<_> = steam_ids
+            .into_iter()
+            .map(|id| self.steam_names.get(&id).cloned())
+            .collect();
+
+        Ok(names.into_iter())
+    }
+}
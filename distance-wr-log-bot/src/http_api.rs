@@ -0,0 +1,468 @@
+use crate::domain::{ChangelistEvent, LevelInfo};
+use crate::name_cache::NameCache;
+use crate::name_history::{NameHistory, NameSighting, NameStyle};
+use crate::persistence::{LoadError, Persistence};
+use crate::record_history::RecordHistory;
+use anyhow::Result;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use distance_util::LeaderboardGameMode;
+use log::{error, info};
+use serde_derive::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+
+/// Serves the persisted data over HTTP until the process is killed.
+pub async fn serve(address: &str, persistence: Box<dyn Persistence + Send>) -> Result<()> {
+    let state = ApiState {
+        persistence: Arc::new(Mutex::new(persistence)),
+        cache: Arc::default(),
+    };
+    let app = Router::new()
+        .route("/changelist", get(changelist))
+        .route("/levels", get(levels))
+        .route("/levels/:leaderboard_name", get(level))
+        .route("/players/:steam_id/records", get(player_records))
+        .route("/players/:steam_id/names", get(player_names_history))
+        .with_state(state);
+
+    let listener = tokio::net::TcpListener::bind(address).await?;
+    info!("Serving the HTTP API on {address}");
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}
+
+/// The persistence the API reads from, shared by all requests, and what was last loaded from it.
+#[derive(Clone)]
+struct ApiState {
+    persistence: Arc<Mutex<Box<dyn Persistence + Send>>>,
+    cache: Arc<Mutex<Option<Arc<StoredData>>>>,
+}
+
+/// Everything the API serves, as loaded at one data version.
+#[derive(Debug)]
+struct StoredData {
+    version: u64,
+    changelist: Vec<ChangelistEvent>,
+    query_results: Vec<LevelInfo>,
+    record_history: RecordHistory,
+    name_cache: NameCache,
+    name_history: NameHistory,
+}
+
+impl ApiState {
+    /// Returns the stored data, only loading it again if an update saved something since it was
+    /// last loaded. Runs on a blocking thread, since the persistence backends do blocking I/O.
+    async fn load(&self) -> Result<Arc<StoredData>, ApiError> {
+        let state = self.clone();
+        tokio::task::spawn_blocking(move || -> Result<_, ApiError> {
+            let persistence = state.persistence.lock().unwrap();
+            let version = persistence.data_version()?;
+            let mut cache = state.cache.lock().unwrap();
+            if let Some(data) = cache.as_ref().filter(|x| x.version == version) {
+                return Ok(Arc::clone(data));
+            }
+
+            let data = Arc::new(StoredData {
+                version,
+                changelist: load_or_default(persistence.load_changelist())?,
+                query_results: load_or_default(persistence.load_query_results())?,
+                record_history: load_or_default(persistence.load_record_history())?,
+                name_cache: load_or_default(persistence.load_name_cache())?,
+                name_history: load_or_default(persistence.load_name_history())?,
+            });
+            *cache = Some(Arc::clone(&data));
+
+            Ok(data)
+        })
+        .await
+        .map_err(|e| ApiError::Internal(e.into()))?
+    }
+}
+
+impl StoredData {
+    /// The player's latest resolved name, including for players who no longer hold a tracked rank.
+    fn player_name(&self, steam_id: u64) -> Option<String> {
+        self.name_cache
+            .get(steam_id)
+            .or_else(|| self.name_history.current(steam_id))
+            .map(str::to_owned)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ChangelistQuery {
+    pub mode: Option<LeaderboardGameMode>,
+    /// Only events for this leaderboard.
+    pub level: Option<String>,
+    /// Only events where this player set or lost the record.
+    pub steam_id: Option<u64>,
+    /// Only workshop levels if true, only official levels if false.
+    pub workshop: Option<bool>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// 1-based page number, newest events first.
+    pub page: Option<usize>,
+    pub per_page: Option<usize>,
+    /// Whether the events show the names players had at the time, or their current names.
+    pub names: NameStyle,
+    /// Include the rank events, which are left out by default.
+    pub rank_events: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangelistPage {
+    pub total: usize,
+    pub page: usize,
+    pub per_page: usize,
+    pub events: Vec<ChangelistEvent>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LevelRecord {
+    pub leaderboard_name: String,
+    pub name: String,
+    pub mode: LeaderboardGameMode,
+    pub workshop_item_id: Option<u64>,
+    pub record: Option<CurrentRecord>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CurrentRecord {
+    pub steam_id: u64,
+    pub player_name: Option<String>,
+    pub score: i32,
+    /// When the record was first seen, if the record history goes back that far.
+    pub since: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct LevelQuery {
+    /// Look up who held the record at this time instead of now.
+    at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlayerRecords {
+    pub steam_id: u64,
+    pub player_name: Option<String>,
+    /// Records the player currently holds.
+    pub current: Vec<LevelRecord>,
+    /// Every record the player ever held, including current ones, oldest first.
+    pub history: Vec<PlayerHolding>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlayerHolding {
+    pub leaderboard_name: String,
+    pub score: i32,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlayerNames {
+    pub steam_id: u64,
+    pub current: Option<String>,
+    /// Every name the player had, oldest first.
+    pub history: Vec<NameSighting>,
+}
+
+#[derive(Debug)]
+enum ApiError {
+    NotFound(String),
+    Internal(anyhow::Error),
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        ApiError::Internal(e)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            ApiError::NotFound(message) => (StatusCode::NOT_FOUND, message),
+            ApiError::Internal(e) => {
+                error!("error handling API request: {e:#}");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "internal server error".to_owned(),
+                )
+            }
+        };
+
+        (status, Json(serde_json::json!({ "error": message }))).into_response()
+    }
+}
+
+async fn changelist(
+    State(state): State<ApiState>,
+    Query(query): Query<ChangelistQuery>,
+) -> Result<Json<ChangelistPage>, ApiError> {
+    let data = state.load().await?;
+    let mut page = filter_changelist(&data.changelist, &query);
+    data.name_history.render(&mut page.events, query.names);
+
+    Ok(Json(page))
+}
+
+async fn levels(State(state): State<ApiState>) -> Result<Json<Vec<LevelRecord>>, ApiError> {
+    let data = state.load().await?;
+
+    Ok(Json(
+        data.query_results
+            .iter()
+            .map(|level_info| current_record(level_info, &data.record_history))
+            .collect(),
+    ))
+}
+
+async fn level(
+    State(state): State<ApiState>,
+    Path(leaderboard_name): Path<String>,
+    Query(query): Query<LevelQuery>,
+) -> Result<Json<LevelRecord>, ApiError> {
+    let data = state.load().await?;
+    let level_info = data
+        .query_results
+        .iter()
+        .find(|x| x.leaderboard_name == leaderboard_name)
+        .ok_or_else(|| ApiError::NotFound(format!("no level {leaderboard_name:?}")))?;
+    let level_record = current_record(level_info, &data.record_history);
+
+    Ok(Json(match query.at {
+        None => level_record,
+        Some(time) => LevelRecord {
+            record: data
+                .record_history
+                .holder_at(&leaderboard_name, time)
+                .map(|holding| CurrentRecord {
+                    steam_id: holding.steam_id,
+                    player_name: data.player_name(holding.steam_id),
+                    score: holding.score,
+                    since: Some(holding.first_seen),
+                }),
+            ..level_record
+        },
+    }))
+}
+
+async fn player_records(
+    State(state): State<ApiState>,
+    Path(steam_id): Path<u64>,
+) -> Result<Json<PlayerRecords>, ApiError> {
+    let data = state.load().await?;
+
+    let current = data
+        .query_results
+        .iter()
+        .map(|level_info| current_record(level_info, &data.record_history))
+        .filter(|x| x.record.as_ref().map(|record| record.steam_id) == Some(steam_id))
+        .collect();
+    let mut history: Vec<_> = data
+        .record_history
+        .iter()
+        .flat_map(|(leaderboard_name, holdings)| {
+            holdings
+                .iter()
+                .filter(|holding| holding.steam_id == steam_id)
+                .map(move |holding| PlayerHolding {
+                    leaderboard_name: leaderboard_name.to_owned(),
+                    score: holding.score,
+                    first_seen: holding.first_seen,
+                    last_seen: holding.last_seen,
+                })
+        })
+        .collect();
+    history.sort_by_key(|x| x.first_seen);
+
+    Ok(Json(PlayerRecords {
+        steam_id,
+        player_name: data.player_name(steam_id),
+        current,
+        history,
+    }))
+}
+
+async fn player_names_history(
+    State(state): State<ApiState>,
+    Path(steam_id): Path<u64>,
+) -> Result<Json<PlayerNames>, ApiError> {
+    let data = state.load().await?;
+
+    Ok(Json(PlayerNames {
+        steam_id,
+        current: data.player_name(steam_id),
+        history: data.name_history.get(steam_id).to_vec(),
+    }))
+}
+
+fn load_or_default<T: Default>(loaded: Result<T, LoadError>) -> Result<T, ApiError> {
+    match loaded {
+        Ok(x) => Ok(x),
+        Err(LoadError::DoesNotExist) => Ok(T::default()),
+        Err(e) => Err(ApiError::Internal(e.into())),
+    }
+}
+
+/// Filters the changelist according to the query, and returns the requested page, newest first.
+pub fn filter_changelist(
+    changelist: &[ChangelistEvent],
+    query: &ChangelistQuery,
+) -> ChangelistPage {
+    let matching: Vec<_> = changelist
+        .iter()
+        .rev()
+        .filter(|event| query.rank_events || !event.kind.is_rank_event())
+        .filter(|event| query.mode.is_none_or(|mode| event.mode == mode))
+        .filter(|event| {
+            query
+                .level
+                .as_ref()
+                .is_none_or(|level| event.leaderboard_name.as_ref() == Some(level))
+        })
+        .filter(|event| {
+            query.steam_id.is_none_or(|steam_id| {
+                [&event.new_record, &event.old_record]
+                    .into_iter()
+                    .flatten()
+                    .any(|record| record.steam_id == steam_id)
+            })
+        })
+        .filter(|event| {
+            query
+                .workshop
+                .is_none_or(|workshop| event.workshop_item_id.is_some() == workshop)
+        })
+        .filter(|event| query.since.is_none_or(|since| event.fetch_time >= since))
+        .filter(|event| query.until.is_none_or(|until| event.fetch_time < until))
+        .collect();
+
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query
+        .per_page
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    ChangelistPage {
+        total: matching.len(),
+        page,
+        per_page,
+        events: matching
+            .into_iter()
+            .skip((page - 1).saturating_mul(per_page))
+            .take(per_page)
+            .cloned()
+            .collect(),
+    }
+}
+
+fn current_record(level_info: &LevelInfo, record_history: &RecordHistory) -> LevelRecord {
+    let record = level_info
+        .leaderboard_response
+        .entries
+        .first()
+        .map(|entry| {
+            let since = record_history
+                .get(&level_info.leaderboard_name)
+                .last()
+                .filter(|x| x.steam_id == entry.steam_id && x.score == entry.score)
+                .map(|x| x.first_seen);
+
+            CurrentRecord {
+                steam_id: entry.steam_id,
+                player_name: entry.player_name.clone(),
+                score: entry.score,
+                since,
+            }
+        });
+
+    LevelRecord {
+        leaderboard_name: level_info.leaderboard_name.clone(),
+        name: level_info.name.clone(),
+        mode: level_info.mode,
+        workshop_item_id: level_info
+            .workshop_response
+            .as_ref()
+            .map(|x| x.published_file_id),
+        record,
+    }
+}
+
+#[test]
+fn test_filter_changelist() {
+    use crate::domain::{ChangelistEventKind, ChangelistRecord};
+    use chrono::TimeZone;
+
+    let event = |day, mode, workshop_item_id, steam_id| ChangelistEvent {
+        kind: ChangelistEventKind::NewRecord,
+        leaderboard_name: Some(format!("level_{day}")),
+        map_name: format!("Level {day}"),
+        mode,
+        map_author: None,
+        map_preview: None,
+        workshop_item_id,
+        steam_id_author: None,
+        new_record: Some(ChangelistRecord {
+            steam_id,
+            player_name: String::new(),
+            score: 0,
+        }),
+        old_record: None,
+        rank: None,
+        fetch_time: Utc.with_ymd_and_hms(2021, 1, day, 0, 0, 0).unwrap(),
+    };
+    let changelist = [
+        event(1, LeaderboardGameMode::Sprint, None, 1),
+        event(2, LeaderboardGameMode::Sprint, Some(10), 2),
+        event(3, LeaderboardGameMode::Stunt, Some(11), 1),
+        event(4, LeaderboardGameMode::Sprint, Some(12), 1),
+        event(5, LeaderboardGameMode::Sprint, Some(13), 1),
+    ];
+    fn days(page: &ChangelistPage) -> Vec<&str> {
+        page.events.iter().map(|x| x.map_name.as_str()).collect()
+    }
+
+    let page = filter_changelist(
+        &changelist,
+        &ChangelistQuery {
+            mode: Some(LeaderboardGameMode::Sprint),
+            steam_id: Some(1),
+            page: Some(2),
+            per_page: Some(2),
+            ..Default::default()
+        },
+    );
+    assert_eq!(page.total, 3);
+    assert_eq!(days(&page), ["Level 1"]);
+
+    let page = filter_changelist(
+        &changelist,
+        &ChangelistQuery {
+            workshop: Some(true),
+            since: Some(Utc.with_ymd_and_hms(2021, 1, 3, 0, 0, 0).unwrap()),
+            until: Some(Utc.with_ymd_and_hms(2021, 1, 5, 0, 0, 0).unwrap()),
+            ..Default::default()
+        },
+    );
+    assert_eq!(days(&page), ["Level 4", "Level 3"]);
+
+    let page = filter_changelist(
+        &changelist,
+        &ChangelistQuery {
+            level: Some("level_2".into()),
+            ..Default::default()
+        },
+    );
+    assert_eq!(days(&page), ["Level 2"]);
+}
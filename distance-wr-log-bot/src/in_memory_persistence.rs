@@ -0,0 +1,142 @@
+use crate::domain::{ChangelistEvent, FetchState, LevelInfo, Notification};
+use crate::name_cache::NameCache;
+use crate::name_history::NameHistory;
+use crate::persistence::{LoadError, Persistence};
+use crate::record_history::RecordHistory;
+use anyhow::Result;
+use std::cell::{Cell, RefCell};
+
+/// A [`Persistence`] implementation that keeps everything in memory. Useful for tests.
+#[derive(Debug, Default)]
+pub struct InMemoryPersistence {
+    query_results: RefCell<Option<Vec<LevelInfo>>>,
+    changelist: RefCell<Option<Vec<ChangelistEvent>>>,
+    outbox: RefCell<Vec<Notification>>,
+    record_history: RefCell<Option<RecordHistory>>,
+    fetch_state: RefCell<Option<FetchState>>,
+    name_cache: RefCell<Option<NameCache>>,
+    name_history: RefCell<Option<NameHistory>>,
+    /// Bumped on every write.
+    version: Cell<u64>,
+}
+
+impl InMemoryPersistence {
+    fn changed(&self) {
+        self.version.set(self.version.get() + 1);
+    }
+}
+
+impl Persistence for InMemoryPersistence {
+    fn load_query_results(&self) -> Result<Vec<LevelInfo>, LoadError> {
+        self.query_results
+            .borrow()
+            .clone()
+            .ok_or(LoadError::DoesNotExist)
+    }
+
+    fn save_query_results(&self, query_results: &[LevelInfo]) -> Result<()> {
+        *self.query_results.borrow_mut() = Some(query_results.to_vec());
+        self.changed();
+        Ok(())
+    }
+
+    fn load_changelist(&self) -> Result<Vec<ChangelistEvent>, LoadError> {
+        self.changelist
+            .borrow()
+            .clone()
+            .ok_or(LoadError::DoesNotExist)
+    }
+
+    fn append_changelist(
+        &self,
+        events: &[ChangelistEvent],
+        notifications: &[Notification],
+    ) -> Result<()> {
+        self.changelist
+            .borrow_mut()
+            .get_or_insert_with(Vec::new)
+            .extend_from_slice(events);
+        let mut outbox = self.outbox.borrow_mut();
+        for notification in notifications {
+            if outbox.iter().all(|x| x.id != notification.id) {
+                outbox.push(notification.clone());
+            }
+        }
+        self.changed();
+        Ok(())
+    }
+
+    fn replace_changelist(&self, events: &[ChangelistEvent]) -> Result<()> {
+        *self.changelist.borrow_mut() = Some(events.to_vec());
+        self.changed();
+        Ok(())
+    }
+
+    fn load_outbox(&self) -> Result<Vec<Notification>> {
+        Ok(self.outbox.borrow().clone())
+    }
+
+    fn remove_from_outbox(&self, ids: &[String]) -> Result<()> {
+        self.outbox
+            .borrow_mut()
+            .retain(|notification| !ids.contains(&notification.id));
+        self.changed();
+        Ok(())
+    }
+
+    fn load_record_history(&self) -> Result<RecordHistory, LoadError> {
+        self.record_history
+            .borrow()
+            .clone()
+            .ok_or(LoadError::DoesNotExist)
+    }
+
+    fn save_record_history(&self, record_history: &RecordHistory) -> Result<()> {
+        *self.record_history.borrow_mut() = Some(record_history.clone());
+        self.changed();
+        Ok(())
+    }
+
+    fn load_fetch_state(&self) -> Result<FetchState, LoadError> {
+        self.fetch_state
+            .borrow()
+            .clone()
+            .ok_or(LoadError::DoesNotExist)
+    }
+
+    fn save_fetch_state(&self, fetch_state: &FetchState) -> Result<()> {
+        *self.fetch_state.borrow_mut() = Some(fetch_state.clone());
+        self.changed();
+        Ok(())
+    }
+
+    fn load_name_cache(&self) -> Result<NameCache, LoadError> {
+        self.name_cache
+            .borrow()
+            .clone()
+            .ok_or(LoadError::DoesNotExist)
+    }
+
+    fn save_name_cache(&self, name_cache: &NameCache) -> Result<()> {
+        *self.name_cache.borrow_mut() = Some(name_cache.clone());
+        self.changed();
+        Ok(())
+    }
+
+    fn load_name_history(&self) -> Result<NameHistory, LoadError> {
+        self.name_history
+            .borrow()
+            .clone()
+            .ok_or(LoadError::DoesNotExist)
+    }
+
+    fn save_name_history(&self, name_history: &NameHistory) -> Result<()> {
+        *self.name_history.borrow_mut() = Some(name_history.clone());
+        self.changed();
+        Ok(())
+    }
+
+    fn data_version(&self) -> Result<u64> {
+        Ok(self.version.get())
+    }
+}
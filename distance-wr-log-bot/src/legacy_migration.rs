@@ -0,0 +1,419 @@
+use crate::domain::{
+    ChangelistEntry, ChangelistEvent, ChangelistEventKind, ChangelistRecord, LevelInfo,
+};
+use crate::file_json_persistence;
+use crate::persistence::{LoadError, Persistence};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
+use distance_util::LeaderboardGameMode;
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::Path;
+
+/// The outcome of converting a legacy changelist.
+#[derive(Debug, Default)]
+pub struct Conversion {
+    pub events: Vec<ChangelistEvent>,
+    pub failures: Vec<ConversionFailure>,
+}
+
+/// A legacy changelist entry that couldn't be converted, kept around so it can be fixed by hand.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversionFailure {
+    /// The position of the entry in the legacy changelist.
+    pub index: usize,
+    pub error: String,
+    pub entry: ChangelistEntry,
+}
+
+/// Imports `changelist.json` and `query_results.json` from `legacy_dir` into `persistence`.
+///
+/// The legacy changelist is backed up first, since the new changelist replaces it, and entries
+/// that can't be converted are written to `migration_failures.json`. A changelist that isn't in
+/// the legacy format at all is rejected without importing anything.
+pub fn migrate(legacy_dir: &Path, persistence: &dyn Persistence) -> Result<()> {
+    let changelist_path = legacy_dir.join("changelist.json");
+    let query_results_path = legacy_dir.join("query_results.json");
+
+    match persistence.load_changelist() {
+        Ok(x) if !x.is_empty() => bail!(
+            "a migrated changelist with {} events already exists",
+            x.len()
+        ),
+        Ok(_) | Err(LoadError::DoesNotExist) | Err(LoadError::NeedsMigration) => {}
+        Err(e) => return Err(e).context("Error loading changelist"),
+    }
+
+    let query_results: Vec<LevelInfo> = match File::open(&query_results_path) {
+        Ok(file) => serde_json::from_reader(file)
+            .with_context(|| format!("couldn't parse {}", query_results_path.display()))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            warn!("No legacy query results found; leaderboard names won't be filled in");
+            Vec::new()
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if !query_results.is_empty() {
+        if let Err(LoadError::DoesNotExist) = persistence.load_query_results() {
+            info!("Importing {} query results", query_results.len());
+            persistence.save_query_results(&query_results)?;
+        }
+    }
+
+    let entries: Vec<ChangelistEntry> = serde_json::from_reader(
+        File::open(&changelist_path)
+            .with_context(|| format!("couldn't open {}", changelist_path.display()))?,
+    )
+    .with_context(|| format!("couldn't parse {}", changelist_path.display()))?;
+
+    let backup_path = legacy_dir.join("changelist_legacy_backup.json");
+    if !backup_path.exists() {
+        info!(
+            "Backing up the legacy changelist to {}",
+            backup_path.display()
+        );
+        fs::copy(&changelist_path, &backup_path)?;
+    }
+
+    let entry_count = entries.len();
+    let conversion = convert_legacy_changelist(entries, &query_results);
+    persistence.replace_changelist(&conversion.events)?;
+    info!(
+        "Migrated {} of {} changelist entries",
+        conversion.events.len(),
+        entry_count
+    );
+
+    if !conversion.failures.is_empty() {
+        for failure in &conversion.failures {
+            warn!(
+                "Changelist entry {} could not be converted: {}",
+                failure.index, failure.error
+            );
+        }
+
+        let failures_path = legacy_dir.join("migration_failures.json");
+        file_json_persistence::save_file(&conversion.failures, &failures_path)?;
+        warn!(
+            "{} entries could not be converted, and were written to {}",
+            conversion.failures.len(),
+            failures_path.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Converts legacy changelist entries to events, using the query results to fill in the
+/// leaderboard names the legacy format doesn't have.
+pub fn convert_legacy_changelist(
+    entries: Vec<ChangelistEntry>,
+    query_results: &[LevelInfo],
+) -> Conversion {
+    let leaderboard_names = LeaderboardNames::new(query_results);
+
+    let mut conversion = Conversion::default();
+    for (index, entry) in entries.into_iter().enumerate() {
+        match convert_entry(&entry, &leaderboard_names) {
+            Ok(event) => conversion.events.push(event),
+            Err(e) => conversion.failures.push(ConversionFailure {
+                index,
+                error: format!("{e:#}"),
+                entry,
+            }),
+        }
+    }
+
+    conversion
+}
+
+/// Entries don't have a kind, since the legacy changelist only ever had records. They're new
+/// records, or first records if there's no old record holder.
+fn convert_entry(
+    entry: &ChangelistEntry,
+    leaderboard_names: &LeaderboardNames,
+) -> Result<ChangelistEvent> {
+    let mode = parse_mode(&entry.mode)?;
+    let workshop_item_id = entry
+        .workshop_item_id
+        .as_deref()
+        .map(str::parse)
+        .transpose()
+        .context("invalid workshop_item_id")?;
+    let steam_id_author = entry
+        .steam_id_author
+        .as_deref()
+        .map(str::parse)
+        .transpose()
+        .context("invalid steam_id_author")?;
+    let new_record = legacy_record(
+        &entry.steam_id_new_recordholder,
+        &entry.new_recordholder,
+        &entry.record_new,
+        mode,
+    )
+    .context("invalid new record")?;
+    let old_record = match (
+        &entry.steam_id_old_recordholder,
+        &entry.old_recordholder,
+        &entry.record_old,
+    ) {
+        (None, None, None) => None,
+        (Some(steam_id), Some(player_name), Some(score)) => {
+            Some(legacy_record(steam_id, player_name, score, mode).context("invalid old record")?)
+        }
+        _ => bail!("the old record is only partially present"),
+    };
+    let fetch_time = DateTime::parse_from_rfc2822(&entry.fetch_time)
+        .with_context(|| format!("invalid fetch_time {:?}", entry.fetch_time))?
+        .with_timezone(&Utc);
+
+    Ok(ChangelistEvent {
+        kind: if old_record.is_some() {
+            ChangelistEventKind::NewRecord
+        } else {
+            ChangelistEventKind::FirstRecord
+        },
+        leaderboard_name: leaderboard_names.get(workshop_item_id, &entry.map_name, mode),
+        map_name: entry.map_name.clone(),
+        mode,
+        map_author: entry.map_author.clone(),
+        map_preview: entry.map_preview.clone(),
+        workshop_item_id,
+        steam_id_author,
+        new_record: Some(new_record),
+        old_record,
+        rank: None,
+        fetch_time,
+    })
+}
+
+/// Looks up leaderboard names by workshop id, or by level name and mode for official levels.
+#[derive(Debug)]
+struct LeaderboardNames<'a> {
+    by_workshop_id: HashMap<u64, &'a str>,
+    by_name: HashMap<(&'a str, LeaderboardGameMode), &'a str>,
+}
+
+impl<'a> LeaderboardNames<'a> {
+    fn new(query_results: &'a [LevelInfo]) -> Self {
+        let mut by_workshop_id = HashMap::new();
+        let mut by_name = HashMap::new();
+        for level_info in query_results {
+            let leaderboard_name = level_info.leaderboard_name.as_str();
+            match &level_info.workshop_response {
+                Some(x) => {
+                    by_workshop_id.insert(x.published_file_id, leaderboard_name);
+                }
+                None => {
+                    by_name.insert(
+                        (level_info.name.as_str(), level_info.mode),
+                        leaderboard_name,
+                    );
+                }
+            }
+        }
+
+        LeaderboardNames {
+            by_workshop_id,
+            by_name,
+        }
+    }
+
+    fn get(
+        &self,
+        workshop_item_id: Option<u64>,
+        map_name: &str,
+        mode: LeaderboardGameMode,
+    ) -> Option<String> {
+        match workshop_item_id {
+            Some(id) => self.by_workshop_id.get(&id),
+            None => self.by_name.get(&(map_name, mode)),
+        }
+        .map(|x| x.to_string())
+    }
+}
+
+fn parse_mode(text: &str) -> Result<LeaderboardGameMode> {
+    [
+        LeaderboardGameMode::Sprint,
+        LeaderboardGameMode::Challenge,
+        LeaderboardGameMode::Stunt,
+    ]
+    .into_iter()
+    .find(|mode| mode.to_string() == text)
+    .ok_or_else(|| anyhow!("unknown mode {text:?}"))
+}
+
+fn legacy_record(
+    steam_id: &str,
+    player_name: &str,
+    score: &str,
+    mode: LeaderboardGameMode,
+) -> Result<ChangelistRecord> {
+    Ok(ChangelistRecord {
+        steam_id: steam_id
+            .parse()
+            .with_context(|| format!("invalid steam id {steam_id:?}"))?,
+        player_name: player_name.to_owned(),
+        score: parse_legacy_score(score, mode)?,
+    })
+}
+
+/// Parses a score formatted with [`distance_util::format_score_legacy`] back into an integer.
+///
+/// The formatting can drop precision, so a few candidates are tried, and the first one that
+/// formats back to the same text wins.
+fn parse_legacy_score(text: &str, mode: LeaderboardGameMode) -> Result<i32> {
+    score_candidates(text)
+        .into_iter()
+        .find(|&score| distance_util::format_score_legacy(score, mode).is_ok_and(|x| x == text))
+        .ok_or_else(|| anyhow!("couldn't parse {text:?} as a {mode} score"))
+}
+
+fn score_candidates(text: &str) -> Vec<i32> {
+    let text = text.trim();
+    let (sign, text) = match text.strip_prefix('-') {
+        Some(x) => (-1, x),
+        None => (1, text),
+    };
+    let mut candidates = Vec::new();
+
+    // Points, possibly with thousands separators and a unit
+    let digits: String = text.chars().filter(char::is_ascii_digit).collect();
+    if let Ok(x) = digits.parse::<i64>() {
+        candidates.push(x);
+    }
+
+    // Times like "1:02:03.45", where the milliseconds may have been cut off
+    let time = text.trim_end_matches(|c: char| !c.is_ascii_digit());
+    let (whole, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let seconds = whole
+        .split(':')
+        .try_fold(0_i64, |acc, part| part.parse::<i64>().map(|x| acc * 60 + x));
+    let milliseconds = format!("{fraction:0<3}")
+        .get(..3)
+        .and_then(|x| x.parse::<i64>().ok());
+    if let (Ok(seconds), Some(milliseconds)) = (seconds, milliseconds) {
+        let time = seconds * 1000 + milliseconds;
+        candidates.extend((0..10).map(|offset| time + offset));
+    }
+
+    candidates
+        .into_iter()
+        .filter_map(|x| i32::try_from(sign * x).ok())
+        .collect()
+}
+
+#[test]
+fn test_convert_legacy_changelist() {
+    use crate::domain;
+    use crate::steamworks::{LeaderboardResponse, WorkshopResponse};
+    use chrono::TimeZone;
+
+    let fetch_time = Utc.with_ymd_and_hms(2020, 5, 17, 12, 30, 0).unwrap();
+    let record = |steam_id, player_name: &str, score| ChangelistRecord {
+        steam_id,
+        player_name: player_name.into(),
+        score,
+    };
+    let events = vec![
+        ChangelistEvent {
+            kind: ChangelistEventKind::NewRecord,
+            leaderboard_name: Some("Broken Symmetry_1_stable".into()),
+            map_name: "Broken Symmetry".into(),
+            mode: LeaderboardGameMode::Sprint,
+            map_author: None,
+            map_preview: None,
+            workshop_item_id: None,
+            steam_id_author: None,
+            new_record: Some(record(2, "Bob", 83_450)),
+            old_record: Some(record(1, "Alice", 90_000)),
+            rank: None,
+            fetch_time,
+        },
+        ChangelistEvent {
+            kind: ChangelistEventKind::FirstRecord,
+            leaderboard_name: Some("stunt_level_8_1_stable".into()),
+            map_name: "Stunt Level".into(),
+            mode: LeaderboardGameMode::Stunt,
+            map_author: Some("Author".into()),
+            map_preview: Some("https://example.com/preview.png".into()),
+            workshop_item_id: Some(100),
+            steam_id_author: Some(3),
+            new_record: Some(record(1, "Alice", 1_234_567)),
+            old_record: None,
+            rank: None,
+            fetch_time,
+        },
+    ];
+    let query_results: Vec<_> = events
+        .iter()
+        .map(|event| LevelInfo {
+            name: event.map_name.clone(),
+            mode: event.mode,
+            leaderboard_name: event.leaderboard_name.clone().unwrap(),
+            workshop_response: event.workshop_item_id.map(|id| WorkshopResponse {
+                published_file_id: id,
+                steam_id_owner: 3,
+                file_name: "Stunt_Level.bytes".into(),
+                title: "Stunt Level".into(),
+                score: 0.5,
+                tags: Box::new(["Stunt".into()]),
+                author_name: Some("Author".into()),
+                preview_url: "https://example.com/preview.png".into(),
+            }),
+            leaderboard_response: LeaderboardResponse {
+                entries: Box::new([]),
+                range_end: LeaderboardResponse::LEGACY_RANGE_END,
+            },
+            timestamp: fetch_time,
+            missing_since: None,
+        })
+        .collect();
+
+    let mut entries = domain::legacy_changelist(&events);
+    let mut broken = entries[0].clone();
+    broken.record_new = "not a time".into();
+    entries.insert(1, broken);
+    let mut partial = entries[0].clone();
+    partial.steam_id_old_recordholder = None;
+    entries.push(partial);
+
+    let conversion = convert_legacy_changelist(entries, &query_results);
+    assert_eq!(
+        serde_json::to_value(&conversion.events).unwrap(),
+        serde_json::to_value(&events).unwrap()
+    );
+    assert_eq!(
+        conversion
+            .failures
+            .iter()
+            .map(|x| x.index)
+            .collect::<Vec<_>>(),
+        [1, 3]
+    );
+}
+
+#[test]
+fn test_migrate_rejects_malformed_changelist() {
+    use crate::file_json_persistence::FileJsonPersistence;
+
+    let dir = tempfile::tempdir().unwrap();
+    // Entries without a new record were never written to the legacy changelist
+    fs::write(
+        dir.path().join("changelist.json"),
+        r#"[{ "map_name": "Level", "mode": "Sprint", "fetch_time": "Sun, 17 May 2020 12:30:00 +0000" }]"#,
+    )
+    .unwrap();
+    let persistence = FileJsonPersistence::new(dir.path());
+
+    assert!(migrate(dir.path(), &persistence).is_err());
+    assert!(matches!(
+        persistence.load_changelist(),
+        Err(LoadError::NeedsMigration)
+    ));
+    assert!(!dir.path().join("changelist_legacy_backup.json").exists());
+}
@@ -5,53 +5,277 @@
     unused_qualifications
 )]
 
-use crate::domain::{ChangelistEntry, LevelInfo};
-use crate::file_json_persistence::{FileJsonPersistence, LoadError};
-use crate::steamworks::Steamworks;
+use crate::data_source::{ConfiguredDataSource, DataSource};
+use crate::discord::DiscordWebhook;
+use crate::domain::{
+    ChangelistEvent, ChangelistEventKind, ChangelistRecord, FetchFailure, FetchFailureKind,
+    FetchState, LevelInfo,
+};
+use crate::feeds::FeedConfig;
+use crate::file_json_persistence::FileJsonPersistence;
+use crate::in_memory_persistence::InMemoryPersistence;
+use crate::name_cache::NameCache;
+use crate::name_history::{NameHistory, NameStyle};
+use crate::persistence::{LoadError, Persistence};
+use crate::record_history::RecordHistory;
+use crate::scheduler::Candidate;
+use crate::snapshot_archive::SnapshotArchive;
+use crate::sqlite_persistence::SqlitePersistence;
+use crate::steamworks::LeaderboardEntry;
 use anyhow::{Context, Result};
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
 use distance_util::LeaderboardGameMode;
+use distance_wr_log_config::{
+    Backend, Config, FeedFormat, Fetch as FetchConfig, RankEvents as RankEventsConfig,
+};
 use futures::{future, stream, Stream, StreamExt, TryStreamExt};
-use if_chain::if_chain;
 use indicatif::ProgressBar;
 use itertools::{EitherOrBoth, Itertools};
 use log::{info, warn};
-use std::cell::Cell;
-use std::collections::{BTreeMap, HashMap};
-use std::env;
-use std::future::Future;
-use std::path::Path;
-use std::time::Duration;
+use std::cell::{Cell, RefCell};
+use std::collections::{btree_map, BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::time::{Duration, Instant};
 use tap::Pipe;
 
+mod commands;
+mod data_source;
+mod discord;
 mod domain;
+mod feeds;
 mod file_json_persistence;
+mod fixture_data_source;
+mod http_api;
+mod in_memory_persistence;
+mod legacy_migration;
+mod name_cache;
+mod name_history;
 mod official_levels;
+mod outbox;
+mod persistence;
+mod player_stats;
+mod record_history;
+mod scheduler;
+mod snapshot_archive;
+mod sqlite_persistence;
 mod steamworks;
+mod wr_rankings;
 
-const QUERY_RESULTS_PATH: &str = "/data/query_results.json";
-const CHANGELIST_PATH: &str = "/data/changelist.json";
+/// Tracks the world records in Distance and maintains the changelist.
+#[derive(Debug, Parser)]
+#[command(version)]
+struct Cli {
+    /// What to do. Defaults to `update`.
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Fetches the latest leaderboards, updates the changelist and saves everything.
+    Update {
+        /// Print the new changelist events instead of saving anything or sending notifications.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Fetches the top of a single leaderboard and prints it as JSON.
+    FetchLevel {
+        leaderboard_name: String,
+        /// How many entries to fetch.
+        #[arg(long, default_value_t = 10)]
+        entries: i32,
+    },
+    /// Prints the stored changelist.
+    Export {
+        #[arg(long, value_enum, default_value_t = commands::ExportFormat::V2)]
+        format: commands::ExportFormat,
+        /// Render the events with the names players had at the time, or with their current names.
+        #[arg(long, value_enum, default_value_t = NameStyle::Historical)]
+        names: NameStyle,
+        /// Write to this file instead of standard output.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Checks the stored data for problems, exiting with an error if there are any.
+    Verify,
+    /// Prints the changelist events between two snapshots, either archived ones or query results
+    /// files.
+    Diff { old: PathBuf, new: PathBuf },
+    /// Recomputes the changelist from the archived snapshots taken between two times, keeping the
+    /// events from outside that range.
+    Replay {
+        /// The first snapshot to use, as an RFC 3339 timestamp. Defaults to the oldest one.
+        #[arg(long)]
+        from: Option<DateTime<Utc>>,
+        /// The last snapshot to use, as an RFC 3339 timestamp. Defaults to the newest one.
+        #[arg(long)]
+        to: Option<DateTime<Utc>>,
+        /// Replay across snapshots further apart than an update, merging the events of the pruned
+        /// updates between them.
+        #[arg(long)]
+        allow_gaps: bool,
+        /// Print the recomputed events instead of saving the changelist.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Imports the legacy changelist and query results into the configured backend.
+    Migrate {
+        /// The directory with the legacy files. Defaults to the data directory.
+        legacy_dir: Option<PathBuf>,
+    },
+    /// Serves the stored data over HTTP.
+    Serve,
+}
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
-    let grpc_address = env::var("GRPC_SERVER_ADDRESS")
-        .expect("environment variable GRPC_SERVER_ADDRESS is not set");
-    let steam_web_api_key =
-        env::var("STEAM_WEB_API_KEY").expect("environment variable STEAM_WEB_API_KEY is not set");
+    let cli = Cli::parse();
+    let config = Config::load()?;
 
-    let steamworks = Steamworks::new(grpc_address, steam_web_api_key).await?;
-    let persistence = FileJsonPersistence::new(QUERY_RESULTS_PATH, CHANGELIST_PATH);
+    match cli.command.unwrap_or(Command::Update { dry_run: false }) {
+        Command::Update { dry_run } => {
+            let persistence = open_persistence(&config)?;
+            let needs_migration = matches!(
+                persistence.load_changelist(),
+                Err(LoadError::NeedsMigration)
+            );
+            if needs_migration && !dry_run {
+                info!("Only a legacy changelist exists; migrating it first");
+                legacy_migration::migrate(&config.paths.data_dir, persistence.as_ref())?;
+            }
+            let outputs = Outputs::from_config(&config)?;
+            let data_source = ConfiguredDataSource::new(&config.steam).await?;
 
-    info!("Starting update procedure");
-    update(&steamworks, &persistence).await?;
-    info!("Finished update procedure");
+            info!("Starting update procedure");
+            update(
+                &data_source,
+                persistence.as_ref(),
+                &config.fetch,
+                &config.rank_events,
+                &outputs,
+                dry_run,
+            )
+            .await?;
+            info!("Finished update procedure");
 
-    Ok(())
+            Ok(())
+        }
+        Command::FetchLevel {
+            leaderboard_name,
+            entries,
+        } => {
+            let data_source = ConfiguredDataSource::new(&config.steam).await?;
+            commands::fetch_level(&data_source, &leaderboard_name, entries).await
+        }
+        Command::Export {
+            format,
+            names,
+            output,
+        } => commands::export(
+            open_persistence(&config)?.as_ref(),
+            format,
+            names,
+            output.as_deref(),
+        ),
+        Command::Verify => commands::verify(open_persistence(&config)?.as_ref()),
+        Command::Diff { old, new } => {
+            commands::diff(&old, &new, rank_events_top(&config.rank_events))
+        }
+        Command::Replay {
+            from,
+            to,
+            allow_gaps,
+            dry_run,
+        } => commands::replay(
+            open_persistence(&config)?.as_ref(),
+            &SnapshotArchive::new(config.paths.snapshots()),
+            from,
+            to,
+            rank_events_top(&config.rank_events),
+            (!allow_gaps)
+                .then(|| config.manager.update_period + config.manager.max_update_duration),
+            dry_run,
+        ),
+        Command::Migrate { legacy_dir } => {
+            let legacy_dir = legacy_dir.unwrap_or_else(|| config.paths.data_dir.clone());
+            info!("Migrating legacy data from {}", legacy_dir.display());
+            legacy_migration::migrate(&legacy_dir, open_persistence(&config)?.as_ref())
+        }
+        Command::Serve => {
+            http_api::serve(config.http_api.address(), open_persistence(&config)?).await
+        }
+    }
+}
+
+fn open_persistence(config: &Config) -> Result<Box<dyn Persistence + Send>> {
+    match config.persistence.backend {
+        Backend::Json => Ok(Box::new(FileJsonPersistence::new(&config.paths.data_dir))),
+        Backend::Sqlite => {
+            let persistence = SqlitePersistence::open(config.paths.sqlite())?
+                .with_changelist_export(config.paths.changelist_export());
+
+            Ok(Box::new(persistence))
+        }
+        Backend::Memory => {
+            warn!("Using in-memory persistence; nothing will be saved");
+            Ok(Box::new(InMemoryPersistence::default()))
+        }
+    }
+}
+
+/// Everything an update writes besides the stored data itself.
+#[derive(Debug, Default)]
+struct Outputs {
+    discord_webhooks: Vec<DiscordWebhook>,
+    feeds: Option<FeedConfig>,
+    snapshots: Option<SnapshotArchive>,
+    /// Where player profiles and rankings are written.
+    stats_dir: Option<PathBuf>,
 }
 
-async fn update(steamworks: &Steamworks, persistence: &FileJsonPersistence) -> Result<()> {
+impl Outputs {
+    fn from_config(config: &Config) -> Result<Self> {
+        let discord_webhooks = match &config.discord.webhooks_path {
+            Some(path) => DiscordWebhook::load_all(path)?,
+            None => Vec::new(),
+        };
+
+        // The in-memory backend is for trying things out, so it leaves the data directory alone
+        let writes_files = config.persistence.backend != Backend::Memory;
+
+        let formats = &config.feeds.formats;
+        let feeds = (writes_files && formats.contains(&FeedFormat::Atom)).then(|| FeedConfig {
+            dir: config.paths.data_dir.clone(),
+            rss: formats.contains(&FeedFormat::Rss),
+            link: config.feeds.link.clone(),
+        });
+
+        let snapshots = config.snapshots.enabled.then(|| {
+            SnapshotArchive::new(config.paths.snapshots()).with_retention(config.snapshots.clone())
+        });
+
+        Ok(Outputs {
+            discord_webhooks,
+            feeds,
+            snapshots,
+            stats_dir: (writes_files && config.stats.enabled)
+                .then(|| config.paths.data_dir.clone()),
+        })
+    }
+}
+
+async fn update(
+    data_source: &impl DataSource,
+    persistence: &dyn Persistence,
+    fetch_config: &FetchConfig,
+    rank_events: &RankEventsConfig,
+    outputs: &Outputs,
+    dry_run: bool,
+) -> Result<()> {
     let old_level_infos = match persistence.load_query_results() {
         Ok(x) => {
             info!("Loaded previous query results");
@@ -82,69 +306,509 @@ async fn update(steamworks: &Steamworks, persistence: &FileJsonPersistence) -> R
         }
     };
 
-    let spinner = ProgressBar::new_spinner();
-    let mut new_level_infos = get_level_infos(steamworks)
-        .inspect(|res| {
-            if let Ok(level_info) = res {
-                spinner.set_message(format!("Fetched level {}", &level_info.name));
+    let mut record_history = match persistence.load_record_history() {
+        Ok(x) => {
+            info!("Loaded record history");
+            x
+        }
+        Err(e) => {
+            if let LoadError::DoesNotExist = e {
+                warn!("No existing record history found");
+                RecordHistory::default()
+            } else {
+                return Err(e).context("Error loading record history");
             }
-        })
-        .try_collect::<Vec<_>>()
-        .await?;
+        }
+    };
+
+    let mut name_cache = match persistence.load_name_cache() {
+        Ok(x) => x,
+        Err(LoadError::DoesNotExist) => NameCache::default(),
+        Err(e) => return Err(e).context("Error loading name cache"),
+    };
+
+    let mut name_history = match persistence.load_name_history() {
+        Ok(x) => x,
+        Err(LoadError::DoesNotExist) => NameHistory::default(),
+        Err(e) => return Err(e).context("Error loading name history"),
+    };
+
+    let mut fetch_state = match persistence.load_fetch_state() {
+        Ok(x) => x,
+        Err(LoadError::DoesNotExist) => FetchState::default(),
+        Err(e) => return Err(e).context("Error loading fetch state"),
+    };
+    let fetch_start = Utc::now();
+    let plan = plan_fetch(
+        fetch_config,
+        &fetch_state,
+        old_level_infos.as_deref(),
+        &changelist,
+        fetch_start,
+    );
+    match plan.workshop_updated_since {
+        None => info!("Fetching all levels"),
+        Some(since) => info!(
+            "Fetching workshop levels updated since {since}, and {} levels due for polling",
+            plan.due.len()
+        ),
+    }
+
+    let deadline = fetch_config.time_budget.map(|x| Instant::now() + x);
+    let fetch_report = FetchReport::default();
+    let mut candidates = list_leaderboards(
+        data_source,
+        fetch_config,
+        &plan,
+        &fetch_state.failures,
+        &fetch_report,
+    )
+    .await?;
+    scheduler::prioritize(
+        &mut candidates,
+        old_level_infos.as_deref().unwrap_or_default(),
+        &changelist,
+        fetch_config,
+        fetch_start,
+    );
+
+    let spinner = ProgressBar::new_spinner();
+    let mut new_level_infos = get_level_infos(
+        data_source,
+        fetch_config,
+        candidates,
+        deadline,
+        &fetch_report,
+    )
+    .inspect(|level_info| spinner.set_message(format!("Fetched level {}", &level_info.name)))
+    .collect::<Vec<_>>()
+    .await;
     spinner.finish_with_message("Finished fetching level information.");
 
+    // Only a full sweep lists every workshop level, so only then can missing ones be noticed
+    let listed_workshop_files = (plan.workshop_updated_since.is_none()
+        && !fetch_report.listing_timed_out.get())
+    .then(|| fetch_report.listed_workshop_files.take());
+    let failed = fetch_report.failed.take();
+    info!(
+        "Polled {} leaderboards, and failed to poll {}",
+        new_level_infos.len(),
+        failed.len()
+    );
+    record_fetch_failures(
+        &mut fetch_state.failures,
+        &new_level_infos,
+        failed,
+        listed_workshop_files.as_ref(),
+        fetch_start,
+    );
+
     info!("Resolving player and author names...");
-    resolve_player_and_author_names(steamworks, &mut new_level_infos).await?;
+    resolve_player_and_author_names(
+        data_source,
+        &mut new_level_infos,
+        old_level_infos.as_deref(),
+        &mut name_cache,
+        &mut name_history,
+        fetch_config,
+        Utc::now(),
+    )
+    .await?;
     info!("Finished resolving player and author names");
 
     // Deal with Steam sometimes failing to return data by supplementing it with the previously stored
     // data.
+    let now = Utc::now();
     if let Some(ref old) = old_level_infos {
-        new_level_infos = add_missing_entries_from(new_level_infos, old.clone());
+        new_level_infos = add_missing_entries_from(
+            new_level_infos,
+            old.clone(),
+            listed_workshop_files.as_ref(),
+            &mut fetch_state.emptied,
+            fetch_config.level_removal_grace_period,
+            now,
+        );
     }
+    log_stale_leaderboards(&fetch_state.failures, &new_level_infos, now);
 
+    let previous_changelist_len = changelist.len();
     if let Some(old_level_infos) = old_level_infos {
         info!("Computing changelist");
-        update_changelist(&mut changelist, &mut new_level_infos, old_level_infos);
+        update_changelist(
+            &mut changelist,
+            &mut new_level_infos,
+            old_level_infos,
+            rank_events_top(rank_events),
+            now,
+        );
+    }
+
+    let new_events = &changelist[previous_changelist_len..];
+    if dry_run {
+        info!("Dry run; printing {} new events", new_events.len());
+        return commands::print_events(new_events);
     }
 
     info!("Saving changelist");
-    persistence.save_changelist(&changelist)?;
+    let notifications = outbox::notifications_for(&outputs.discord_webhooks, new_events, now);
+    persistence.append_changelist(new_events, &notifications)?;
+
+    if let Some(feed_config) = &outputs.feeds {
+        info!("Writing feeds");
+        if let Err(e) = feeds::write_feeds(
+            feed_config,
+            &changelist,
+            &changelist[previous_changelist_len..],
+        ) {
+            warn!("Error writing feeds: {e:#}");
+        }
+    }
+
+    if let Some(stats_dir) = &outputs.stats_dir {
+        info!("Writing player profiles");
+        let profiles = player_stats::compute_profiles(&changelist, &new_level_infos, now);
+        if let Err(e) = player_stats::write_profiles(stats_dir, &profiles) {
+            warn!("Error writing player profiles: {e:#}");
+        }
+
+        info!("Writing record rankings");
+        if let Err(e) = wr_rankings::write_rankings(stats_dir, &new_level_infos, now) {
+            warn!("Error writing record rankings: {e:#}");
+        }
+    }
+
+    if let Err(e) = outbox::deliver(&outputs.discord_webhooks, persistence).await {
+        warn!("Error delivering notifications: {e:#}");
+    }
+
+    info!("Updating record history");
+    for leaderboard_name in record_history.observe(&new_level_infos) {
+        let previous_reign = record_history.reign_durations(leaderboard_name).nth_back(1);
+        if let Some((previous, duration)) = previous_reign {
+            info!(
+                "The previous record on {leaderboard_name} by {} stood for {}",
+                previous.steam_id,
+                humantime::format_duration(duration.to_std().unwrap_or_default())
+            );
+        }
+    }
+    persistence.save_record_history(&record_history)?;
+
+    if let Some(archive) = &outputs.snapshots {
+        info!("Archiving level info");
+        archive.save(&new_level_infos, now)?;
+        let pruned = archive.prune(now)?;
+        if pruned > 0 {
+            info!("Pruned {pruned} archived snapshots");
+        }
+    }
 
     info!("Saving level info");
     persistence.save_query_results(&new_level_infos)?;
 
+    // Levels that were listed but couldn't be polled are retried as fetch failures, so only the
+    // listing itself has to be complete
+    if !fetch_report.listing_timed_out.get() {
+        fetch_state.workshop_listed_at = Some(fetch_start);
+        if plan.workshop_updated_since.is_none() {
+            fetch_state.last_full_sweep = Some(fetch_start);
+        }
+    }
+    persistence.save_fetch_state(&fetch_state)?;
+    persistence.save_name_cache(&name_cache)?;
+    persistence.save_name_history(&name_history)?;
+
     Ok(())
 }
 
-fn get_level_infos(steamworks: &Steamworks) -> impl Stream<Item = Result<LevelInfo>> + '_ {
-    const MAX_BUFFER: usize = 4;
-    const TIMEOUT: Duration = Duration::from_secs(60);
+/// Which workshop levels an update lists and polls.
+#[derive(Debug, Default)]
+struct FetchPlan {
+    /// `None` for a full sweep, which lists the whole workshop and polls every level. Otherwise,
+    /// only the workshop levels updated since then are listed and polled, along with `due`.
+    workshop_updated_since: Option<DateTime<Utc>>,
 
-    let official_levels = get_official_levels(steamworks)
-        .pipe(stream::iter)
-        .buffer_unordered(MAX_BUFFER);
-    let workshop_levels = get_workshop_levels(steamworks)
-        .buffer_unordered(MAX_BUFFER)
-        .filter_map(|x| future::ready(x.transpose()));
+    /// Previously fetched workshop levels to poll even if they weren't updated.
+    due: Vec<LevelInfo>,
+}
 
-    official_levels.chain(workshop_levels).pipe(|stream| {
-        tokio_stream::StreamExt::timeout(stream, TIMEOUT)
-            .take_while(|timeout_result| {
-                let timed_out = timeout_result.is_err();
-                if timed_out {
-                    warn!("Skipping some levels that took too long to fetch");
-                }
+/// Decides between a full sweep and an incremental fetch. Incremental fetches poll the hot levels,
+/// whose record changed recently, and the cold levels that haven't been polled in a while.
+fn plan_fetch(
+    config: &FetchConfig,
+    state: &FetchState,
+    old_level_infos: Option<&[LevelInfo]>,
+    changelist: &[ChangelistEvent],
+    now: DateTime<Utc>,
+) -> FetchPlan {
+    let age = |time: DateTime<Utc>| (now - time).to_std().unwrap_or_default();
+
+    let (true, Some(old_level_infos), Some(listed_at), Some(last_full_sweep)) = (
+        config.incremental,
+        old_level_infos,
+        state.workshop_listed_at,
+        state.last_full_sweep,
+    ) else {
+        return FetchPlan::default();
+    };
+    if age(last_full_sweep) >= config.full_sweep_interval {
+        return FetchPlan::default();
+    }
+
+    let hot: HashSet<&str> = changelist
+        .iter()
+        .filter(|event| age(event.fetch_time) < config.hot_period)
+        .filter_map(|event| event.leaderboard_name.as_deref())
+        .collect();
+    let due = old_level_infos
+        .iter()
+        .filter(|level| level.workshop_response.is_some())
+        .filter(|level| {
+            hot.contains(level.leaderboard_name.as_str())
+                || age(level.timestamp) >= config.cold_poll_interval
+        })
+        .cloned()
+        .collect();
+
+    FetchPlan {
+        workshop_updated_since: Some(
+            listed_at - chrono::Duration::from_std(WORKSHOP_LISTING_OVERLAP).unwrap(),
+        ),
+        due,
+    }
+}
+
+/// How far back incremental workshop listings reach past the previous listing, in case Steam's
+/// update times lag behind.
+const WORKSHOP_LISTING_OVERLAP: Duration = Duration::from_secs(10 * 60);
+
+/// What happened while fetching level information, besides the levels that were fetched.
+#[derive(Debug, Default)]
+struct FetchReport {
+    /// Whether listing the workshop was cut short because it took too long.
+    listing_timed_out: Cell<bool>,
+
+    /// The leaderboards that couldn't be polled, and why.
+    failed: RefCell<Vec<(Candidate, FetchFailureKind)>>,
+
+    /// The published file ids of every workshop level that was listed, including ones whose
+    /// leaderboards couldn't be fetched.
+    listed_workshop_files: RefCell<HashSet<u64>>,
+}
 
-                future::ready(!timed_out)
+/// Lists the leaderboards to poll: the official levels, the listed workshop levels, the ones that
+/// are due, and the ones that failed before.
+async fn list_leaderboards(
+    data_source: &impl DataSource,
+    config: &FetchConfig,
+    plan: &FetchPlan,
+    failures: &BTreeMap<String, FetchFailure>,
+    report: &FetchReport,
+) -> Result<Vec<Candidate>> {
+    let mut candidates: Vec<_> = get_official_levels().collect();
+
+    let listing = tokio_stream::StreamExt::timeout(
+        get_workshop_levels(data_source, plan, &report.listed_workshop_files),
+        config.timeout,
+    );
+    let mut listing = pin!(listing);
+    while let Some(timeout_result) = listing.next().await {
+        let Ok(candidate) = timeout_result else {
+            warn!("Stopped listing workshop levels that took too long to list");
+            report.listing_timed_out.set(true);
+            break;
+        };
+        candidates.push(candidate?);
+    }
+
+    let listed_workshop_files = report.listed_workshop_files.borrow();
+    candidates.extend(
+        plan.due
+            .iter()
+            .filter(|level| {
+                let published_file_id = level.workshop_response.as_ref().unwrap().published_file_id;
+                !listed_workshop_files.contains(&published_file_id)
             })
-            .map(|timeout_result| timeout_result.unwrap())
-    })
+            .map(|level| Candidate {
+                name: level.name.clone(),
+                mode: level.mode,
+                leaderboard_name: level.leaderboard_name.clone(),
+                workshop_response: level.workshop_response.clone(),
+            }),
+    );
+
+    let listed: HashSet<_> = candidates
+        .iter()
+        .map(|candidate| candidate.leaderboard_name.clone())
+        .collect();
+    candidates.extend(
+        failures
+            .iter()
+            .filter(|(leaderboard_name, _)| !listed.contains(*leaderboard_name))
+            .map(|(leaderboard_name, failure)| Candidate {
+                name: failure.name.clone(),
+                mode: failure.mode,
+                leaderboard_name: leaderboard_name.clone(),
+                workshop_response: failure.workshop_response.clone(),
+            }),
+    );
+
+    Ok(candidates)
+}
+
+/// Polls the leaderboards in order, until `deadline` if there is one. The leaderboards that
+/// couldn't be polled are added to the report.
+fn get_level_infos<'a>(
+    data_source: &'a impl DataSource,
+    config: &FetchConfig,
+    candidates: Vec<Candidate>,
+    deadline: Option<Instant>,
+    report: &'a FetchReport,
+) -> impl Stream<Item = LevelInfo> + 'a {
+    let timeout = config.timeout;
+    let official_entries = config.official_leaderboard_entries;
+    let workshop_entries = config.workshop_leaderboard_entries;
+    stream::iter(candidates)
+        .map(move |candidate| async move {
+            let leaderboard_response = if deadline.is_some_and(|x| Instant::now() >= x) {
+                Err(FetchFailureKind::Skipped)
+            } else {
+                let entries = if candidate.workshop_response.is_some() {
+                    workshop_entries
+                } else {
+                    official_entries
+                };
+                let request = data_source.get_leaderboard_range(
+                    &candidate.leaderboard_name,
+                    1,
+                    i32::try_from(entries).unwrap_or(i32::MAX),
+                );
+                match tokio::time::timeout(timeout, request).await {
+                    Ok(Ok(x)) => Ok(x),
+                    Ok(Err(e)) => Err(FetchFailureKind::Error {
+                        message: format!("{e:#}"),
+                    }),
+                    Err(_) => Err(FetchFailureKind::Timeout),
+                }
+            };
+
+            match leaderboard_response {
+                Ok(leaderboard_response) => Some(LevelInfo {
+                    name: candidate.name,
+                    mode: candidate.mode,
+                    leaderboard_name: candidate.leaderboard_name,
+                    workshop_response: candidate.workshop_response,
+                    leaderboard_response,
+                    timestamp: Utc::now(),
+                    missing_since: None,
+                }),
+                Err(kind) => {
+                    report.failed.borrow_mut().push((candidate, kind));
+                    None
+                }
+            }
+        })
+        .buffer_unordered(config.max_concurrent_requests)
+        .filter_map(future::ready)
+}
+
+/// Updates the tracked fetch failures with the outcome of this update's polls.
+///
+/// `listed_workshop_files` is `None` unless the workshop listing was complete, in which case
+/// failures of workshop levels that weren't listed are dropped, as those levels were removed.
+fn record_fetch_failures(
+    failures: &mut BTreeMap<String, FetchFailure>,
+    fetched: &[LevelInfo],
+    failed: Vec<(Candidate, FetchFailureKind)>,
+    listed_workshop_files: Option<&HashSet<u64>>,
+    now: DateTime<Utc>,
+) {
+    for level in fetched {
+        failures.remove(&level.leaderboard_name);
+    }
+
+    for (candidate, kind) in failed {
+        match failures.entry(candidate.leaderboard_name) {
+            btree_map::Entry::Occupied(mut entry) => {
+                let failure = entry.get_mut();
+                failure.workshop_response = candidate.workshop_response;
+                failure.kind = kind;
+                failure.attempts += 1;
+            }
+            btree_map::Entry::Vacant(entry) => {
+                entry.insert(FetchFailure {
+                    name: candidate.name,
+                    mode: candidate.mode,
+                    workshop_response: candidate.workshop_response,
+                    kind,
+                    since: now,
+                    attempts: 1,
+                });
+            }
+        }
+    }
+
+    if let Some(listed) = listed_workshop_files {
+        failures.retain(|_, failure| {
+            failure
+                .workshop_response
+                .as_ref()
+                .is_none_or(|workshop| listed.contains(&workshop.published_file_id))
+        });
+    }
 }
 
+/// Logs the leaderboards whose data is stale because they couldn't be polled, and for how long.
+fn log_stale_leaderboards(
+    failures: &BTreeMap<String, FetchFailure>,
+    level_infos: &[LevelInfo],
+    now: DateTime<Utc>,
+) {
+    let last_fetched: HashMap<&str, DateTime<Utc>> = level_infos
+        .iter()
+        .map(|level| (level.leaderboard_name.as_str(), level.timestamp))
+        .collect();
+    let mut stale: Vec<_> = failures
+        .iter()
+        .map(|(leaderboard_name, failure)| {
+            let fetched_at = last_fetched.get(leaderboard_name.as_str()).copied();
+            let stale_for = (now - fetched_at.unwrap_or(failure.since))
+                .to_std()
+                .unwrap_or_default();
+            (leaderboard_name, failure, fetched_at.is_some(), stale_for)
+        })
+        .collect();
+    stale.sort_by_key(|&(_, _, _, stale_for)| std::cmp::Reverse(stale_for));
+
+    for (leaderboard_name, failure, has_data, stale_for) in stale {
+        let stale_for = humantime::format_duration(Duration::from_secs(stale_for.as_secs()));
+        let message = if has_data {
+            format!("{leaderboard_name} is stale by {stale_for}")
+        } else {
+            format!("{leaderboard_name} has had no data for {stale_for}")
+        };
+        let message = format!(
+            "{message}; {} after {} attempts",
+            failure.kind, failure.attempts
+        );
+        match failure.kind {
+            FetchFailureKind::Skipped => info!("{message}"),
+            FetchFailureKind::Error { .. } | FetchFailureKind::Timeout => warn!("{message}"),
+        }
+    }
+}
+
+/// Fills in the player and author names from the name cache, after resolving the ids that aren't
+/// cached, that appear in a leaderboard that changed, or whose name is older than the TTL. Ids that
+/// Steam doesn't resolve keep their last known name. Resolved names are added to the name history.
 async fn resolve_player_and_author_names(
-    steamworks: &Steamworks,
+    data_source: &impl DataSource,
     level_infos: &mut [LevelInfo],
+    old_level_infos: Option<&[LevelInfo]>,
+    name_cache: &mut NameCache,
+    name_history: &mut NameHistory,
+    config: &FetchConfig,
+    now: DateTime<Utc>,
 ) -> Result<()> {
     let author_ids = level_infos
         .iter()
@@ -156,43 +820,85 @@ async fn resolve_player_and_author_names(
         .flat_map(|level| level.leaderboard_response.entries.iter())
         .map(|entry| entry.steam_id);
 
-    let id_to_name_map: HashMap<u64, Cell<Option<String>>> = author_ids
-        .chain(player_ids)
-        .map(|id| (id, Cell::new(None)))
+    let old_entries: HashMap<&str, &[LeaderboardEntry]> = old_level_infos
+        .unwrap_or_default()
+        .iter()
+        .map(|level| {
+            (
+                level.leaderboard_name.as_str(),
+                &*level.leaderboard_response.entries,
+            )
+        })
+        .collect();
+    let changed_ids: HashSet<u64> = level_infos
+        .iter()
+        .filter(|level| {
+            let entries = &level.leaderboard_response.entries;
+            old_entries
+                .get(level.leaderboard_name.as_str())
+                .is_none_or(|old| {
+                    !entries
+                        .iter()
+                        .map(|x| (x.steam_id, x.score))
+                        .eq(old.iter().map(|x| (x.steam_id, x.score)))
+                })
+        })
+        .flat_map(|level| level.leaderboard_response.entries.iter())
+        .map(|entry| entry.steam_id)
         .collect();
-    for chunk in &id_to_name_map.keys().chunks(4_096) {
-        let (chunk_1, chunk_2) = chunk.tee();
-        let names = steamworks
-            .resolve_steam_names(chunk_1.copied().collect())
-            .await?;
 
-        for (id, name) in chunk_2.zip(names) {
-            id_to_name_map.get(id).unwrap().set(name);
+    let ids_to_refresh = name_cache.ids_to_refresh(
+        author_ids.chain(player_ids),
+        &changed_ids,
+        config.name_ttl,
+        now,
+    );
+    info!("Resolving {} names", ids_to_refresh.len());
+    for chunk in ids_to_refresh.chunks(config.name_resolution_chunk_size) {
+        match data_source.resolve_steam_names(chunk.to_vec()).await {
+            Ok(names) => {
+                let names: Vec<_> = names.collect();
+                for (&steam_id, name) in chunk.iter().zip(&names) {
+                    let Some(name) = name else { continue };
+                    if let Some(previous) = name_history.observe(steam_id, name, now) {
+                        info!("{steam_id} was renamed from {previous:?} to {name:?}");
+                    }
+                }
+                name_cache.update(chunk.iter().copied().zip(names), now);
+            }
+            Err(e) => warn!("Error resolving names, using the last known ones: {e:#}"),
         }
     }
 
-    level_infos
-        .iter_mut()
-        .filter_map(|level| level.workshop_response.as_mut())
-        .for_each(|workshop| {
-            let cell = id_to_name_map.get(&workshop.steam_id_owner).unwrap();
-            workshop.author_name = Some(cell.take().unwrap_or_else(|| "[unknown]".into()));
-            cell.set(workshop.author_name.clone());
-        });
-
-    level_infos
-        .iter_mut()
-        .flat_map(|level| level.leaderboard_response.entries.iter_mut())
-        .for_each(|entry| {
-            let cell = id_to_name_map.get(&entry.steam_id).unwrap();
-            entry.player_name = Some(cell.take().unwrap_or_else(|| "[unknown]".into()));
-            cell.set(entry.player_name.clone());
-        });
+    let name = |steam_id| name_cache.get(steam_id).unwrap_or("[unknown]").to_owned();
+    for level in level_infos.iter_mut() {
+        if let Some(workshop) = &mut level.workshop_response {
+            workshop.author_name = Some(name(workshop.steam_id_owner));
+        }
+        for entry in level.leaderboard_response.entries.iter_mut() {
+            entry.player_name = Some(name(entry.steam_id));
+        }
+    }
 
     Ok(())
 }
 
-fn add_missing_entries_from(mut new: Vec<LevelInfo>, mut old: Vec<LevelInfo>) -> Vec<LevelInfo> {
+/// Merges the previous query results into the new ones, for levels Steam didn't return data for.
+///
+/// Workshop levels that are missing from a complete workshop listing are only kept for
+/// `grace_period`, after which they're dropped. `listed_workshop_files` is `None` if the listing
+/// wasn't complete, in which case every old level is kept.
+///
+/// Leaderboards that come back empty keep their old entries for `grace_period` too, since Steam
+/// sometimes returns no entries by mistake. `emptied` tracks since when they've been empty.
+fn add_missing_entries_from(
+    mut new: Vec<LevelInfo>,
+    mut old: Vec<LevelInfo>,
+    listed_workshop_files: Option<&HashSet<u64>>,
+    emptied: &mut BTreeMap<String, DateTime<Utc>>,
+    grace_period: Duration,
+    now: DateTime<Utc>,
+) -> Vec<LevelInfo> {
     let sort = |x: &mut [LevelInfo]| {
         x.sort_unstable_by(|a, b| a.leaderboard_name.cmp(&b.leaderboard_name))
     };
@@ -200,27 +906,56 @@ fn add_missing_entries_from(mut new: Vec<LevelInfo>, mut old: Vec<LevelInfo>) ->
     sort(&mut new);
     sort(&mut old);
 
-    new.into_iter()
+    let merged: Vec<_> = new
+        .into_iter()
         .merge_join_by(old, |a, b| a.leaderboard_name.cmp(&b.leaderboard_name))
-        .map(|x| match x {
-            EitherOrBoth::Both(new, old) => {
-                if new.leaderboard_response.entries.len() == 0
-                    && old.leaderboard_response.entries.len() > 0
+        .filter_map(|x| match x {
+            EitherOrBoth::Both(new, mut old) => {
+                if new.leaderboard_response.entries.is_empty()
+                    && !old.leaderboard_response.entries.is_empty()
                 {
-                    old
-                } else {
-                    new
+                    let emptied_since = *emptied.entry(new.leaderboard_name.clone()).or_insert(now);
+                    let emptied_for = (now - emptied_since).to_std().unwrap_or_default();
+                    if emptied_for < grace_period {
+                        old.missing_since = None;
+                        return Some(old);
+                    }
+                }
+                emptied.remove(&new.leaderboard_name);
+                Some(new)
+            }
+            EitherOrBoth::Left(new) => Some(new),
+            EitherOrBoth::Right(mut old) => {
+                let Some(workshop_response) = &old.workshop_response else {
+                    return Some(old);
+                };
+
+                match listed_workshop_files {
+                    Some(listed) if !listed.contains(&workshop_response.published_file_id) => {
+                        let missing_since = *old.missing_since.get_or_insert(now);
+                        let missing_for = (now - missing_since).to_std().unwrap_or_default();
+                        (missing_for < grace_period).then_some(old)
+                    }
+                    Some(_) => {
+                        old.missing_since = None;
+                        Some(old)
+                    }
+                    None => Some(old),
                 }
             }
-            EitherOrBoth::Left(x) | EitherOrBoth::Right(x) => x,
         })
-        .collect()
+        .collect();
+
+    emptied.retain(|leaderboard_name, _| {
+        merged
+            .binary_search_by(|x| x.leaderboard_name.cmp(leaderboard_name))
+            .is_ok()
+    });
+    merged
 }
 
-fn get_official_levels(
-    steamworks: &Steamworks,
-) -> impl Iterator<Item = impl Future<Output = Result<LevelInfo>> + '_> + '_ {
-    official_levels::iter().map(move |(level_name, mode)| {
+fn get_official_levels() -> impl Iterator<Item = Candidate> {
+    official_levels::iter().map(|(level_name, mode)| {
         let leaderboard_name = distance_util::create_leaderboard_name_string(
             level_name, mode, None,
         )
@@ -230,28 +965,30 @@ fn get_official_levels(
             )
         });
 
-        async move {
-            let leaderboard_response = steamworks
-                .get_leaderboard_range(&leaderboard_name, 1, 2)
-                .await?;
-
-            Ok(LevelInfo {
-                name: level_name.to_owned(),
-                mode,
-                leaderboard_name,
-                workshop_response: None,
-                leaderboard_response,
-                timestamp: Utc::now(),
-            })
+        Candidate {
+            name: level_name.to_owned(),
+            mode,
+            leaderboard_name,
+            workshop_response: None,
         }
     })
 }
 
-fn get_workshop_levels(
-    steamworks: &Steamworks,
-) -> impl Stream<Item = impl Future<Output = Result<Option<LevelInfo>>> + '_> + '_ {
-    let level_infos = steamworks
-        .get_all_workshop_sprint_challenge_stunt_levels()
+fn get_workshop_levels<'a>(
+    data_source: &'a impl DataSource,
+    plan: &FetchPlan,
+    listed_workshop_files: &'a RefCell<HashSet<u64>>,
+) -> impl Stream<Item = Result<Candidate>> + 'a {
+    let listing = match plan.workshop_updated_since {
+        None => future::Either::Left(data_source.get_all_workshop_sprint_challenge_stunt_levels()),
+        Some(since) => future::Either::Right(data_source.get_workshop_levels_updated_since(since)),
+    };
+    listing
+        .inspect_ok(|workshop_response| {
+            listed_workshop_files
+                .borrow_mut()
+                .insert(workshop_response.published_file_id);
+        })
         .map_ok(|workshop_response| {
             [
                 LeaderboardGameMode::Sprint,
@@ -272,35 +1009,38 @@ fn get_workshop_levels(
                     Some(workshop_response.steam_id_owner),
                 )
                 .ok()
-                .map(|leaderboard_name| (workshop_response.clone(), *mode, leaderboard_name))
+                .map(|leaderboard_name| Candidate {
+                    name: workshop_response.title.clone(),
+                    mode: *mode,
+                    leaderboard_name,
+                    workshop_response: Some(workshop_response.clone()),
+                })
             })
             .map(Ok)
             .pipe(stream::iter)
         })
-        .try_flatten();
-
-    level_infos.map(|x: Result<_>| async {
-        let (workshop_response, mode, leaderboard_name) = x?;
-        steamworks
-            .get_leaderboard_range(&leaderboard_name, 1, 2)
-            .await
-            .ok()
-            .map(|leaderboard_response| LevelInfo {
-                name: workshop_response.title.clone(),
-                mode,
-                leaderboard_name,
-                workshop_response: Some(workshop_response),
-                leaderboard_response,
-                timestamp: Utc::now(),
-            })
-            .pipe(Ok)
-    })
+        .try_flatten()
 }
 
+/// The number of top ranks rank events are created for, if they're enabled.
+fn rank_events_top(config: &RankEventsConfig) -> Option<i32> {
+    config
+        .enabled
+        .then(|| i32::try_from(config.top).unwrap_or(i32::MAX))
+}
+
+/// Adds the events between the old and new query results to the changelist. Rank events are only
+/// added if `rank_events_top` is set, with it as the number of top ranks.
+///
+/// A workshop level counts as published on its first successful fetch, when none of its
+/// leaderboards are in the old query results. Those keep every level fetched before until it's
+/// removed, so levels whose first polls failed or were skipped are still published once.
 fn update_changelist(
-    changelist: &mut Vec<ChangelistEntry>,
+    changelist: &mut Vec<ChangelistEvent>,
     new: &mut [LevelInfo],
     old: Vec<LevelInfo>,
+    rank_events_top: Option<i32>,
+    now: DateTime<Utc>,
 ) {
     new.sort_by_key(|level_info| {
         level_info
@@ -309,75 +1049,218 @@ fn update_changelist(
             .map(|x| x.published_file_id)
             .unwrap_or(0)
     });
+    let old_published_file_ids: HashSet<u64> = old
+        .iter()
+        .filter_map(|level_info| level_info.workshop_response.as_ref())
+        .map(|x| x.published_file_id)
+        .collect();
+    let new_leaderboard_names: HashSet<&str> = new
+        .iter()
+        .map(|level_info| level_info.leaderboard_name.as_str())
+        .collect();
     let old: BTreeMap<_, _> = old
         .into_iter()
         .map(|level_info| (level_info.leaderboard_name.clone(), level_info))
         .collect();
 
-    let entries = new.iter().filter_map(|level_info| {
-        let LevelInfo {
-            name,
-            mode,
-            leaderboard_name,
-            workshop_response,
-            leaderboard_response,
-            timestamp,
-        } = level_info;
-        let first_entry = if let Some(x) = leaderboard_response.entries.first() {
-            x.clone()
-        } else {
-            return None;
-        };
+    // A workshop level has a leaderboard per mode, but it's only published or removed once
+    let mut removed_file_ids = HashSet::new();
+    let removed_entries = old
+        .values()
+        .filter(|level_info| !new_leaderboard_names.contains(level_info.leaderboard_name.as_str()))
+        .filter(|level_info| {
+            level_info
+                .workshop_response
+                .as_ref()
+                .is_none_or(|x| removed_file_ids.insert(x.published_file_id))
+        })
+        .map(|level_info| {
+            let last_record = level_info.leaderboard_response.entries.first();
+            ChangelistEvent {
+                old_record: last_record.map(changelist_record),
+                ..level_changelist_event(ChangelistEventKind::LevelRemoved, level_info, now)
+            }
+        });
 
-        let (old_recordholder, record_old, steam_id_old_recordholder) = if_chain! {
-            if let Some(level_info_old) = old.get(leaderboard_name);
-            if let Some(previous_first_entry) = level_info_old.leaderboard_response.entries.first();
-            then {
-                if is_score_better(first_entry.score, previous_first_entry.score, *mode) {
-                    (Some(previous_first_entry.player_name.as_ref().unwrap().clone()),
-                        Some(distance_util::format_score_legacy(previous_first_entry.score, *mode).unwrap()),
-                        Some(format!("{}", previous_first_entry.steam_id)))
-                } else {
-                    return None;
-                }
-            } else {
-                (None, None, None)
+    // Workshop levels that already got their published event from another mode
+    let mut published_file_ids = HashSet::new();
+    let entries = new.iter().flat_map(|level_info| {
+        // If the old snapshot has no workshop levels at all, we can't tell which ones are new
+        let published = level_info
+            .workshop_response
+            .as_ref()
+            .filter(|_| !old_published_file_ids.is_empty())
+            .filter(|x| !old_published_file_ids.contains(&x.published_file_id))
+            .filter(|x| published_file_ids.insert(x.published_file_id))
+            .map(|_| {
+                level_changelist_event(
+                    ChangelistEventKind::LevelPublished,
+                    level_info,
+                    level_info.timestamp,
+                )
+            });
+        let level_info_old = old.get(&level_info.leaderboard_name);
+        let record = record_changelist_event(level_info, level_info_old);
+        let ranks = match (rank_events_top, level_info_old) {
+            (Some(top), Some(level_info_old)) => {
+                rank_changelist_events(level_info, level_info_old, top)
             }
+            _ => Vec::new(),
         };
 
-        Some(ChangelistEntry {
-            map_name: name.clone(),
-            map_author: workshop_response.as_ref().map(|x| x.author_name.as_ref().unwrap().clone()),
-            map_preview: workshop_response.as_ref().map(|x| x.preview_url.clone()),
-            mode: format!("{mode}"),
-            new_recordholder: first_entry.player_name.unwrap(),
-            old_recordholder,
-            record_new: distance_util::format_score_legacy(first_entry.score, *mode).unwrap(),
-            record_old,
-            workshop_item_id: workshop_response
-                .as_ref()
-                .map(|x| format!("{}", x.published_file_id)),
-            steam_id_author: workshop_response
-                .as_ref()
-                .map(|x| format!("{}", x.steam_id_owner)),
-            steam_id_new_recordholder: format!("{}", first_entry.steam_id),
-            steam_id_old_recordholder,
-            fetch_time: timestamp.to_rfc2822(),
-        })
+        // The entries get reversed below
+        ranks.into_iter().rev().chain(record).chain(published)
     });
 
-    let entries: Vec<_> = entries
-        .filter(|new_entry| {
-            changelist
-                .iter()
-                .all(|existing_entry| !new_entry.is_likely_a_duplicate_of(existing_entry))
-        })
+    let entries: Vec<_> = removed_entries
+        .chain(entries)
+        .filter(|new_entry| !is_duplicate(new_entry, changelist))
         .rev()
         .collect();
 
     changelist.extend(entries);
 }
 
+/// Whether the changelist already has the event, e.g. because an update was interrupted after
+/// saving the changelist but before saving the query results.
+///
+/// Events without a new record can legitimately happen again, like a level that's removed,
+/// republished and removed again, or a leaderboard that's wiped twice. So they're only compared
+/// to the last event about the same level or leaderboard.
+fn is_duplicate(event: &ChangelistEvent, changelist: &[ChangelistEvent]) -> bool {
+    if event.new_record.is_some() {
+        changelist
+            .iter()
+            .any(|existing| event.is_likely_a_duplicate_of(existing))
+    } else {
+        changelist
+            .iter()
+            .rev()
+            .find(|existing| event.has_same_subject_as(existing))
+            .is_some_and(|existing| event.is_likely_a_duplicate_of(existing))
+    }
+}
+
+/// Creates a changelist event if the #1 on the level changed since the old snapshot.
+///
+/// The old #1 was removed if the new one has a worse score, or if the leaderboard is empty now.
+/// A different player tying the old #1 isn't an event.
+fn record_changelist_event(
+    level_info: &LevelInfo,
+    level_info_old: Option<&LevelInfo>,
+) -> Option<ChangelistEvent> {
+    let first_entry = level_info.leaderboard_response.entries.first();
+    let previous_first_entry = level_info_old.and_then(|x| x.leaderboard_response.entries.first());
+    let mode = level_info.mode;
+
+    let kind = match (first_entry, previous_first_entry) {
+        (Some(_), None) => ChangelistEventKind::FirstRecord,
+        (Some(first), Some(previous)) if is_score_better(first.score, previous.score, mode) => {
+            ChangelistEventKind::NewRecord
+        }
+        (Some(first), Some(previous)) if is_score_better(previous.score, first.score, mode) => {
+            ChangelistEventKind::RecordRemoved
+        }
+        // `add_missing_entries_from` only lets a leaderboard become empty after the grace period
+        (None, Some(_)) => ChangelistEventKind::RecordRemoved,
+        _ => return None,
+    };
+
+    Some(ChangelistEvent {
+        new_record: first_entry.map(changelist_record),
+        old_record: previous_first_entry.map(changelist_record),
+        ..level_changelist_event(kind, level_info, level_info.timestamp)
+    })
+}
+
+/// Creates changelist events for the players below the #1 whose score improved since the old
+/// snapshot: a [`ChangelistEventKind::NewTopEntry`] for players who moved into the `top` ranks,
+/// and a [`ChangelistEventKind::PersonalBest`] for the others that were already in the fetched
+/// range.
+///
+/// Players that weren't in the old entries only count if the old range reached their new rank,
+/// since they may have just been beyond it.
+fn rank_changelist_events(
+    level_info: &LevelInfo,
+    level_info_old: &LevelInfo,
+    top: i32,
+) -> Vec<ChangelistEvent> {
+    let old_response = &level_info_old.leaderboard_response;
+    if old_response.entries.is_empty() {
+        return Vec::new();
+    }
+    let old_entries: HashMap<u64, &LeaderboardEntry> = old_response
+        .entries
+        .iter()
+        .map(|entry| (entry.steam_id, entry))
+        .collect();
+
+    level_info
+        .leaderboard_response
+        .entries
+        .iter()
+        .filter(|entry| entry.global_rank > 1)
+        .filter_map(|entry| {
+            let old_entry = old_entries.get(&entry.steam_id).copied();
+            let improved = match old_entry {
+                Some(old_entry) => is_score_better(entry.score, old_entry.score, level_info.mode),
+                None => entry.global_rank <= old_response.range_end,
+            };
+            if !improved {
+                return None;
+            }
+
+            let was_in_top = old_entry.is_some_and(|x| x.global_rank <= top);
+            let kind = if entry.global_rank <= top && !was_in_top {
+                ChangelistEventKind::NewTopEntry
+            } else if old_entry.is_some() {
+                ChangelistEventKind::PersonalBest
+            } else {
+                return None;
+            };
+
+            Some(ChangelistEvent {
+                new_record: Some(changelist_record(entry)),
+                old_record: old_entry.map(changelist_record),
+                rank: Some(entry.global_rank),
+                ..level_changelist_event(kind, level_info, level_info.timestamp)
+            })
+        })
+        .collect()
+}
+
+/// Creates a changelist event about the level itself, with no records set.
+fn level_changelist_event(
+    kind: ChangelistEventKind,
+    level_info: &LevelInfo,
+    fetch_time: DateTime<Utc>,
+) -> ChangelistEvent {
+    let workshop_response = level_info.workshop_response.as_ref();
+
+    ChangelistEvent {
+        kind,
+        leaderboard_name: Some(level_info.leaderboard_name.clone()),
+        map_name: level_info.name.clone(),
+        mode: level_info.mode,
+        map_author: workshop_response.and_then(|x| x.author_name.clone()),
+        map_preview: workshop_response.map(|x| x.preview_url.clone()),
+        workshop_item_id: workshop_response.map(|x| x.published_file_id),
+        steam_id_author: workshop_response.map(|x| x.steam_id_owner),
+        new_record: None,
+        old_record: None,
+        rank: None,
+        fetch_time,
+    }
+}
+
+fn changelist_record(entry: &LeaderboardEntry) -> ChangelistRecord {
+    ChangelistRecord {
+        steam_id: entry.steam_id,
+        player_name: entry.player_name.clone().unwrap_or_default(),
+        score: entry.score,
+    }
+}
+
 fn is_score_better(this_score: i32, other_score: i32, game_mode: LeaderboardGameMode) -> bool {
     match game_mode {
         LeaderboardGameMode::Sprint | LeaderboardGameMode::Challenge => this_score < other_score,
@@ -396,3 +1279,614 @@ fn remove_bytes_extension(level: &str) -> &str {
 fn test_remove_bytes_extension() {
     assert_eq!(remove_bytes_extension("some_level.bytes"), "some_level");
 }
+
+#[cfg(test)]
+fn test_workshop_level(published_file_id: u64, file_name: &str) -> steamworks::WorkshopResponse {
+    steamworks::WorkshopResponse {
+        published_file_id,
+        steam_id_owner: 1,
+        file_name: format!("{file_name}.bytes"),
+        title: file_name.replace('_', " "),
+        score: 0.5,
+        tags: Box::new(["Sprint".into()]),
+        author_name: None,
+        preview_url: String::new(),
+    }
+}
+
+/// A leaderboard whose entries are the given steam ids and scores, ranked in order.
+#[cfg(test)]
+fn test_leaderboard(entries: &[(u64, i32)]) -> steamworks::LeaderboardResponse {
+    steamworks::LeaderboardResponse {
+        entries: entries
+            .iter()
+            .zip(1..)
+            .map(|(&(steam_id, score), global_rank)| LeaderboardEntry {
+                steam_id,
+                global_rank,
+                score,
+                player_name: Some(format!("Player {steam_id}")),
+            })
+            .collect(),
+        range_end: i32::try_from(entries.len())
+            .unwrap()
+            .max(steamworks::LeaderboardResponse::LEGACY_RANGE_END),
+    }
+}
+
+/// An official sprint level with the given leaderboard entries, polled now. Tests set whatever
+/// else they need with struct update syntax.
+#[cfg(test)]
+fn test_level_info(leaderboard_name: &str, entries: &[(u64, i32)]) -> LevelInfo {
+    LevelInfo {
+        name: leaderboard_name.to_owned(),
+        mode: LeaderboardGameMode::Sprint,
+        leaderboard_name: leaderboard_name.to_owned(),
+        workshop_response: None,
+        leaderboard_response: test_leaderboard(entries),
+        timestamp: Utc::now(),
+        missing_since: None,
+    }
+}
+
+#[tokio::test]
+async fn test_update_with_fixture_data() {
+    use crate::fixture_data_source::FixtureDataSource;
+    use crate::steamworks::LeaderboardResponse;
+
+    fn leaderboard(steam_id: u64, score: i32) -> LeaderboardResponse {
+        LeaderboardResponse {
+            entries: Box::new([LeaderboardEntry {
+                steam_id,
+                global_rank: 1,
+                score,
+                player_name: None,
+            }]),
+            range_end: 2,
+        }
+    }
+
+    let persistence = InMemoryPersistence::default();
+
+    let leaderboard_name = |level| {
+        distance_util::create_leaderboard_name_string(level, LeaderboardGameMode::Sprint, Some(1))
+            .unwrap()
+    };
+    let mut fixture = FixtureDataSource {
+        workshop_levels: vec![test_workshop_level(100, "Test_Level")],
+        steam_names: [(1, "Author"), (2, "Alice"), (3, "Bob")]
+            .into_iter()
+            .map(|(id, name)| (id, name.to_owned()))
+            .collect(),
+        ..FixtureDataSource::default()
+    };
+
+    fixture
+        .leaderboards
+        .insert(leaderboard_name("Test_Level"), leaderboard(2, 10_000));
+    update(
+        &fixture,
+        &persistence,
+        &FetchConfig::default(),
+        &RankEventsConfig::default(),
+        &Outputs::default(),
+        false,
+    )
+    .await
+    .unwrap();
+    assert!(persistence.load_changelist().unwrap().is_empty());
+
+    fixture
+        .leaderboards
+        .insert(leaderboard_name("Test_Level"), leaderboard(3, 9_000));
+    update(
+        &fixture,
+        &persistence,
+        &FetchConfig::default(),
+        &RankEventsConfig::default(),
+        &Outputs::default(),
+        false,
+    )
+    .await
+    .unwrap();
+    let changelist = persistence.load_changelist().unwrap();
+    assert_eq!(changelist.len(), 1);
+    assert_eq!(changelist[0].kind, ChangelistEventKind::NewRecord);
+    assert_eq!(changelist[0].map_name, "Test Level");
+    assert_eq!(
+        changelist[0]
+            .new_record
+            .as_ref()
+            .map(|r| r.player_name.as_str()),
+        Some("Bob")
+    );
+    assert_eq!(
+        changelist[0]
+            .old_record
+            .as_ref()
+            .map(|r| r.player_name.as_str()),
+        Some("Alice")
+    );
+
+    // Bob's record gets wiped, and a new level gets published
+    fixture
+        .leaderboards
+        .insert(leaderboard_name("Test_Level"), leaderboard(2, 10_000));
+    fixture
+        .workshop_levels
+        .push(test_workshop_level(200, "Another_Level"));
+    fixture
+        .leaderboards
+        .insert(leaderboard_name("Another_Level"), leaderboard(3, 20_000));
+    update(
+        &fixture,
+        &persistence,
+        &FetchConfig::default(),
+        &RankEventsConfig::default(),
+        &Outputs::default(),
+        false,
+    )
+    .await
+    .unwrap();
+    let changelist = persistence.load_changelist().unwrap();
+    let new_entries: Vec<_> = changelist[1..]
+        .iter()
+        .map(|x| {
+            (
+                x.kind,
+                x.map_name.as_str(),
+                x.new_record.as_ref().map(|r| r.player_name.as_str()),
+            )
+        })
+        .collect();
+    assert_eq!(
+        new_entries,
+        [
+            (ChangelistEventKind::LevelPublished, "Another Level", None),
+            (
+                ChangelistEventKind::FirstRecord,
+                "Another Level",
+                Some("Bob")
+            ),
+            (
+                ChangelistEventKind::RecordRemoved,
+                "Test Level",
+                Some("Alice")
+            ),
+        ]
+    );
+    assert_eq!(
+        changelist[3]
+            .old_record
+            .as_ref()
+            .map(|r| r.player_name.as_str()),
+        Some("Bob")
+    );
+}
+
+#[test]
+fn test_update_changelist_first_fetch() {
+    let level = |published_file_id| LevelInfo {
+        workshop_response: Some(test_workshop_level(published_file_id, "level")),
+        ..test_level_info(&format!("level_{published_file_id}"), &[(1, 10_000)])
+    };
+
+    // Level 2 was published a while ago, but its polls kept failing until now
+    let mut changelist = Vec::new();
+    update_changelist(
+        &mut changelist,
+        &mut [level(1), level(2)],
+        vec![level(1)],
+        None,
+        Utc::now(),
+    );
+    assert_eq!(
+        changelist
+            .iter()
+            .map(|x| (x.kind, x.workshop_item_id))
+            .collect::<Vec<_>>(),
+        [
+            (ChangelistEventKind::LevelPublished, Some(2)),
+            (ChangelistEventKind::FirstRecord, Some(2)),
+        ]
+    );
+}
+
+#[test]
+fn test_update_changelist_level_events() {
+    let level = |published_file_id, mode: LeaderboardGameMode| LevelInfo {
+        mode,
+        workshop_response: Some(test_workshop_level(published_file_id, "level")),
+        ..test_level_info(&format!("level_{published_file_id}_{mode}"), &[])
+    };
+    let both_modes = |published_file_id| {
+        [LeaderboardGameMode::Sprint, LeaderboardGameMode::Challenge]
+            .map(|mode| level(published_file_id, mode))
+    };
+
+    // Level 2 replaces level 1, and each has two leaderboards
+    let mut changelist = Vec::new();
+    update_changelist(
+        &mut changelist,
+        &mut both_modes(2),
+        both_modes(1).into(),
+        None,
+        Utc::now(),
+    );
+    assert_eq!(
+        changelist
+            .iter()
+            .map(|x| (x.kind, x.workshop_item_id))
+            .collect::<Vec<_>>(),
+        [
+            (ChangelistEventKind::LevelPublished, Some(2)),
+            (ChangelistEventKind::LevelRemoved, Some(1)),
+        ]
+    );
+}
+
+#[test]
+fn test_update_changelist_level_republished() {
+    let level = |published_file_id| LevelInfo {
+        workshop_response: Some(test_workshop_level(published_file_id, "level")),
+        ..test_level_info(&format!("level_{published_file_id}"), &[])
+    };
+    let with_level_2 = || vec![level(1), level(2)];
+    let without_level_2 = || vec![level(1)];
+
+    // Level 2 is removed, republished, and removed again. Updating twice with the same query
+    // results, as after an interrupted update, doesn't repeat the last removal.
+    let mut changelist = Vec::new();
+    for (mut new, old) in [
+        (without_level_2(), with_level_2()),
+        (with_level_2(), without_level_2()),
+        (without_level_2(), with_level_2()),
+        (without_level_2(), with_level_2()),
+    ] {
+        update_changelist(&mut changelist, &mut new, old, None, Utc::now());
+    }
+    assert_eq!(
+        changelist
+            .iter()
+            .map(|x| (x.kind, x.workshop_item_id))
+            .collect::<Vec<_>>(),
+        [
+            (ChangelistEventKind::LevelRemoved, Some(2)),
+            (ChangelistEventKind::LevelPublished, Some(2)),
+            (ChangelistEventKind::LevelRemoved, Some(2)),
+        ]
+    );
+}
+
+#[test]
+fn test_record_changelist_event() {
+    let kind = |new: &[(u64, i32)], old: &[(u64, i32)]| {
+        record_changelist_event(
+            &test_level_info("level", new),
+            Some(&test_level_info("level", old)),
+        )
+        .map(|x| x.kind)
+    };
+
+    assert_eq!(
+        kind(&[(1, 10_000)], &[]),
+        Some(ChangelistEventKind::FirstRecord)
+    );
+    assert_eq!(
+        kind(&[(2, 9_000)], &[(1, 10_000)]),
+        Some(ChangelistEventKind::NewRecord)
+    );
+    assert_eq!(
+        kind(&[(2, 11_000)], &[(1, 10_000)]),
+        Some(ChangelistEventKind::RecordRemoved)
+    );
+    assert_eq!(
+        kind(&[], &[(1, 10_000)]),
+        Some(ChangelistEventKind::RecordRemoved)
+    );
+    // Another player with the same score taking the top spot isn't a new record
+    assert_eq!(kind(&[(2, 10_000), (1, 10_000)], &[(1, 10_000)]), None);
+    assert_eq!(kind(&[(1, 10_000)], &[(1, 10_000)]), None);
+}
+
+#[test]
+fn test_add_missing_entries_from() {
+    use crate::steamworks::LeaderboardResponse;
+
+    let level = |published_file_id| LevelInfo {
+        name: String::new(),
+        mode: LeaderboardGameMode::Sprint,
+        leaderboard_name: format!("level_{published_file_id}"),
+        workshop_response: Some(test_workshop_level(published_file_id, "level")),
+        leaderboard_response: LeaderboardResponse {
+            entries: Box::new([]),
+            range_end: 2,
+        },
+        timestamp: Utc::now(),
+        missing_since: None,
+    };
+    let names = |level_infos: &[LevelInfo]| {
+        level_infos
+            .iter()
+            .map(|x| x.leaderboard_name.clone())
+            .collect::<Vec<_>>()
+    };
+    let grace_period = FetchConfig::default().level_removal_grace_period;
+    let start = Utc::now();
+    let listed = HashSet::from([1]);
+    let mut emptied = BTreeMap::new();
+
+    // Level 2 stays around during the grace period...
+    let merged = add_missing_entries_from(
+        vec![level(1)],
+        vec![level(1), level(2)],
+        Some(&listed),
+        &mut emptied,
+        grace_period,
+        start,
+    );
+    assert_eq!(names(&merged), ["level_1", "level_2"]);
+    assert_eq!(merged[1].missing_since, Some(start));
+
+    // ...isn't considered missing if the workshop listing was incomplete...
+    let later = start + chrono::Duration::days(2);
+    let kept = add_missing_entries_from(
+        vec![level(1)],
+        merged.clone(),
+        None,
+        &mut emptied,
+        grace_period,
+        later,
+    );
+    assert_eq!(names(&kept), ["level_1", "level_2"]);
+
+    // ...and is dropped once the grace period is over
+    let merged = add_missing_entries_from(
+        vec![level(1)],
+        merged,
+        Some(&listed),
+        &mut emptied,
+        grace_period,
+        later,
+    );
+    assert_eq!(names(&merged), ["level_1"]);
+
+    // A leaderboard that comes back empty keeps its entries during the grace period too
+    let with_entries = |published_file_id| LevelInfo {
+        leaderboard_response: test_leaderboard(&[(1, 10_000)]),
+        ..level(published_file_id)
+    };
+    let kept = add_missing_entries_from(
+        vec![level(1)],
+        vec![with_entries(1)],
+        None,
+        &mut emptied,
+        grace_period,
+        start,
+    );
+    assert_eq!(kept[0].leaderboard_response.entries.len(), 1);
+    assert_eq!(emptied.get("level_1"), Some(&start));
+    let emptied_now = add_missing_entries_from(
+        vec![level(1)],
+        kept,
+        None,
+        &mut emptied,
+        grace_period,
+        later,
+    );
+    assert!(emptied_now[0].leaderboard_response.entries.is_empty());
+    assert!(emptied.is_empty());
+}
+
+#[test]
+fn test_rank_changelist_events() {
+    use crate::steamworks::LeaderboardResponse;
+
+    let level = |entries: &[(u64, i32)], range_end| LevelInfo {
+        name: String::new(),
+        mode: LeaderboardGameMode::Sprint,
+        leaderboard_name: "level".into(),
+        workshop_response: None,
+        leaderboard_response: LeaderboardResponse {
+            entries: entries
+                .iter()
+                .zip(1..)
+                .map(|(&(steam_id, score), global_rank)| LeaderboardEntry {
+                    steam_id,
+                    global_rank,
+                    score,
+                    player_name: None,
+                })
+                .collect(),
+            range_end,
+        },
+        timestamp: Utc::now(),
+        missing_since: None,
+    };
+    let old = level(
+        &[(100, 10_000), (200, 11_000), (300, 12_000), (600, 12_500)],
+        4,
+    );
+    let new = level(
+        &[
+            (100, 10_000),
+            // New to the fetched range
+            (400, 10_500),
+            // Moved up from #4
+            (600, 10_800),
+            // Only pushed down
+            (200, 11_000),
+            // Improved, but was already in the top 3
+            (300, 11_500),
+            // Beyond the old range, so it may not be new
+            (700, 13_000),
+        ],
+        6,
+    );
+
+    let events: Vec<_> = rank_changelist_events(&new, &old, 3)
+        .into_iter()
+        .map(|x| {
+            (
+                x.kind,
+                x.new_record.unwrap().steam_id,
+                x.rank,
+                x.old_record.map(|x| x.score),
+            )
+        })
+        .collect();
+    assert_eq!(
+        events,
+        [
+            (ChangelistEventKind::NewTopEntry, 400, Some(2), None),
+            (ChangelistEventKind::NewTopEntry, 600, Some(3), Some(12_500)),
+            (
+                ChangelistEventKind::PersonalBest,
+                300,
+                Some(5),
+                Some(12_000)
+            ),
+        ]
+    );
+
+    // Nothing to compare against
+    assert!(rank_changelist_events(&new, &level(&[], 4), 3).is_empty());
+}
+
+#[test]
+fn test_plan_fetch() {
+    use crate::steamworks::LeaderboardResponse;
+
+    let now = Utc::now();
+    let ago = |minutes| now - chrono::Duration::minutes(minutes);
+    let level = |published_file_id: Option<u64>, polled_minutes_ago| LevelInfo {
+        name: String::new(),
+        mode: LeaderboardGameMode::Sprint,
+        leaderboard_name: format!("level_{published_file_id:?}"),
+        workshop_response: published_file_id.map(|id| test_workshop_level(id, "level")),
+        leaderboard_response: LeaderboardResponse {
+            entries: Box::new([]),
+            range_end: 2,
+        },
+        timestamp: ago(polled_minutes_ago),
+        missing_since: None,
+    };
+    let old = [
+        // Hot, since its record changed yesterday
+        level(Some(1), 5),
+        // Cold and polled recently
+        level(Some(2), 5),
+        // Cold and due
+        level(Some(3), 2 * 60),
+        // Official levels are always polled anyway
+        level(None, 2 * 60),
+    ];
+    let changelist = [ChangelistEvent {
+        kind: ChangelistEventKind::NewRecord,
+        leaderboard_name: Some(old[0].leaderboard_name.clone()),
+        map_name: String::new(),
+        mode: LeaderboardGameMode::Sprint,
+        map_author: None,
+        map_preview: None,
+        workshop_item_id: Some(1),
+        steam_id_author: None,
+        new_record: None,
+        old_record: None,
+        rank: None,
+        fetch_time: ago(24 * 60),
+    }];
+    let config = FetchConfig {
+        incremental: true,
+        ..FetchConfig::default()
+    };
+    let state = FetchState {
+        last_full_sweep: Some(ago(60)),
+        workshop_listed_at: Some(ago(5)),
+        ..FetchState::default()
+    };
+
+    let plan = plan_fetch(&config, &state, Some(&old), &changelist, now);
+    assert_eq!(plan.workshop_updated_since, Some(ago(15)));
+    assert_eq!(
+        plan.due
+            .iter()
+            .map(|x| x.leaderboard_name.as_str())
+            .collect::<Vec<_>>(),
+        ["level_Some(1)", "level_Some(3)"]
+    );
+
+    // Full sweeps happen without old results, once the interval is over, or when disabled
+    let full_sweep = |config, state, old| {
+        let plan = plan_fetch(config, state, old, &changelist, now);
+        plan.workshop_updated_since.is_none() && plan.due.is_empty()
+    };
+    assert!(full_sweep(&config, &state, None));
+    let overdue = FetchState {
+        last_full_sweep: Some(ago(7 * 60)),
+        ..state.clone()
+    };
+    assert!(full_sweep(&config, &overdue, Some(&old)));
+    let disabled = FetchConfig {
+        incremental: false,
+        ..config.clone()
+    };
+    assert!(full_sweep(&disabled, &state, Some(&old)));
+}
+
+#[test]
+fn test_record_fetch_failures() {
+    use crate::steamworks::LeaderboardResponse;
+
+    let start = Utc::now();
+    let later = start + chrono::Duration::minutes(5);
+    let candidate = |published_file_id| Candidate {
+        name: String::new(),
+        mode: LeaderboardGameMode::Sprint,
+        leaderboard_name: format!("level_{published_file_id}"),
+        workshop_response: Some(test_workshop_level(published_file_id, "level")),
+    };
+    let fetched = |published_file_id| LevelInfo {
+        name: String::new(),
+        mode: LeaderboardGameMode::Sprint,
+        leaderboard_name: format!("level_{published_file_id}"),
+        workshop_response: Some(test_workshop_level(published_file_id, "level")),
+        leaderboard_response: LeaderboardResponse {
+            entries: Box::new([]),
+            range_end: 2,
+        },
+        timestamp: later,
+        missing_since: None,
+    };
+    let error = FetchFailureKind::Error {
+        message: "oops".into(),
+    };
+
+    let mut failures = BTreeMap::new();
+    record_fetch_failures(
+        &mut failures,
+        &[],
+        vec![
+            (candidate(1), error.clone()),
+            (candidate(2), FetchFailureKind::Skipped),
+            (candidate(3), FetchFailureKind::Timeout),
+        ],
+        None,
+        start,
+    );
+    assert_eq!(failures.len(), 3);
+
+    // Level 1 fails again, level 2 succeeds, and level 3 was removed from the workshop
+    record_fetch_failures(
+        &mut failures,
+        &[fetched(2)],
+        vec![(candidate(1), FetchFailureKind::Timeout)],
+        Some(&HashSet::from([1, 2])),
+        later,
+    );
+    assert_eq!(failures.keys().collect::<Vec<_>>(), ["level_1"]);
+    let failure = &failures["level_1"];
+    assert_eq!(
+        (&failure.kind, failure.since, failure.attempts),
+        (&FetchFailureKind::Timeout, start, 2)
+    );
+}
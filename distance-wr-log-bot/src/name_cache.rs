@@ -0,0 +1,102 @@
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+
+/// The last name Steam resolved for each player and level author, keyed by steam id.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct NameCache {
+    names: BTreeMap<u64, CachedName>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedName {
+    pub name: String,
+    pub resolved_at: DateTime<Utc>,
+}
+
+impl NameCache {
+    pub fn from_names(names: impl IntoIterator<Item = (u64, CachedName)>) -> Self {
+        NameCache {
+            names: names.into_iter().collect(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &CachedName)> {
+        self.names.iter().map(|(&steam_id, name)| (steam_id, name))
+    }
+
+    pub fn get(&self, steam_id: u64) -> Option<&str> {
+        self.names.get(&steam_id).map(|x| x.name.as_str())
+    }
+
+    /// Picks the ids out of `steam_ids` whose names have to be resolved: the ones that were never
+    /// resolved, the ones in `changed`, and the ones resolved more than `ttl` ago.
+    pub fn ids_to_refresh(
+        &self,
+        steam_ids: impl IntoIterator<Item = u64>,
+        changed: &HashSet<u64>,
+        ttl: Duration,
+        now: DateTime<Utc>,
+    ) -> Vec<u64> {
+        let mut seen = HashSet::new();
+        steam_ids
+            .into_iter()
+            .filter(|&steam_id| seen.insert(steam_id))
+            .filter(|steam_id| match self.names.get(steam_id) {
+                None => true,
+                Some(cached) => {
+                    changed.contains(steam_id)
+                        || (now - cached.resolved_at).to_std().unwrap_or_default() >= ttl
+                }
+            })
+            .collect()
+    }
+
+    /// Stores the names Steam resolved. Ids it couldn't resolve keep their last known name, and are
+    /// tried again on the next refresh.
+    pub fn update(
+        &mut self,
+        resolved: impl IntoIterator<Item = (u64, Option<String>)>,
+        now: DateTime<Utc>,
+    ) {
+        for (steam_id, name) in resolved {
+            if let Some(name) = name {
+                self.names.insert(
+                    steam_id,
+                    CachedName {
+                        name,
+                        resolved_at: now,
+                    },
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_name_cache() {
+    let start = Utc::now();
+    let ttl = Duration::from_secs(24 * 60 * 60);
+    let mut cache = NameCache::default();
+    cache.update([(1, Some("Alice".into())), (2, Some("Bob".into()))], start);
+
+    let later = start + chrono::Duration::hours(1);
+    assert_eq!(
+        cache.ids_to_refresh([1, 2, 3, 3], &HashSet::from([2]), ttl, later),
+        [2, 3]
+    );
+
+    // Bob couldn't be resolved, so the old name is kept, and refreshed again later
+    cache.update([(2, None), (3, Some("Carol".into()))], later);
+    assert_eq!(cache.get(2), Some("Bob"));
+    assert_eq!(cache.get(3), Some("Carol"));
+
+    let much_later = start + chrono::Duration::days(2);
+    cache.update([(3, Some("Carol".into()))], much_later);
+    assert_eq!(
+        cache.ids_to_refresh([1, 2, 3], &HashSet::new(), ttl, much_later),
+        [1, 2]
+    );
+}
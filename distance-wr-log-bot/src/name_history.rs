@@ -0,0 +1,157 @@
+use crate::domain::ChangelistEvent;
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Every name Steam resolved for each player and level author, keyed by steam id.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct NameHistory {
+    players: BTreeMap<u64, Vec<NameSighting>>,
+}
+
+/// A name as resolved across one or more consecutive resolutions.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NameSighting {
+    pub name: String,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// Which names the changelist is rendered with.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum NameStyle {
+    /// The names players had when the events happened, as stored.
+    #[default]
+    Historical,
+    /// The names players have now.
+    Current,
+}
+
+impl NameHistory {
+    pub fn from_sightings(sightings: impl IntoIterator<Item = (u64, Vec<NameSighting>)>) -> Self {
+        NameHistory {
+            players: sightings.into_iter().collect(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &[NameSighting])> {
+        self.players
+            .iter()
+            .map(|(&steam_id, sightings)| (steam_id, sightings.as_slice()))
+    }
+
+    /// The names a player had, oldest first.
+    pub fn get(&self, steam_id: u64) -> &[NameSighting] {
+        self.players
+            .get(&steam_id)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    pub fn current(&self, steam_id: u64) -> Option<&str> {
+        self.get(steam_id).last().map(|x| x.name.as_str())
+    }
+
+    /// Records a name Steam resolved. Returns the previous name if the player was renamed.
+    pub fn observe(&mut self, steam_id: u64, name: &str, now: DateTime<Utc>) -> Option<String> {
+        let sightings = self.players.entry(steam_id).or_default();
+        let previous = match sightings.last_mut() {
+            Some(last) if last.name == name => {
+                last.last_seen = last.last_seen.max(now);
+                return None;
+            }
+            Some(last) => Some(last.name.clone()),
+            None => None,
+        };
+
+        sightings.push(NameSighting {
+            name: name.to_owned(),
+            first_seen: now,
+            last_seen: now,
+        });
+
+        previous
+    }
+
+    /// Replaces the names in the events with the given style of names. Names of players with no
+    /// history are left as they are.
+    pub fn render(&self, events: &mut [ChangelistEvent], style: NameStyle) {
+        if style == NameStyle::Historical {
+            return;
+        }
+
+        for event in events {
+            if let Some(name) = event.steam_id_author.and_then(|x| self.current(x)) {
+                event.map_author = Some(name.to_owned());
+            }
+            for record in [&mut event.new_record, &mut event.old_record]
+                .into_iter()
+                .flatten()
+            {
+                if let Some(name) = self.current(record.steam_id) {
+                    record.player_name = name.to_owned();
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_name_history() {
+    use crate::domain::{ChangelistEventKind, ChangelistRecord};
+    use distance_util::LeaderboardGameMode;
+
+    let start = Utc::now();
+    let time = |hours| start + chrono::Duration::hours(hours);
+
+    let mut history = NameHistory::default();
+    assert_eq!(history.observe(1, "Alice", time(0)), None);
+    assert_eq!(history.observe(1, "Alice", time(1)), None);
+    assert_eq!(history.observe(1, "Alicia", time(2)), Some("Alice".into()));
+    assert_eq!(
+        history.get(1),
+        [
+            NameSighting {
+                name: "Alice".into(),
+                first_seen: time(0),
+                last_seen: time(1),
+            },
+            NameSighting {
+                name: "Alicia".into(),
+                first_seen: time(2),
+                last_seen: time(2),
+            },
+        ]
+    );
+
+    let record = |steam_id, player_name: &str| ChangelistRecord {
+        steam_id,
+        player_name: player_name.to_owned(),
+        score: 60_000,
+    };
+    let event = ChangelistEvent {
+        kind: ChangelistEventKind::NewRecord,
+        leaderboard_name: None,
+        map_name: "Level".into(),
+        mode: LeaderboardGameMode::Sprint,
+        map_author: Some("Alice".into()),
+        map_preview: None,
+        workshop_item_id: Some(10),
+        steam_id_author: Some(1),
+        new_record: Some(record(1, "Alice")),
+        old_record: Some(record(2, "Bob")),
+        rank: None,
+        fetch_time: time(0),
+    };
+
+    let mut events = [event.clone()];
+    history.render(&mut events, NameStyle::Historical);
+    assert_eq!(events[0].new_record.as_ref().unwrap().player_name, "Alice");
+
+    history.render(&mut events, NameStyle::Current);
+    assert_eq!(events[0].map_author.as_deref(), Some("Alicia"));
+    assert_eq!(events[0].new_record.as_ref().unwrap().player_name, "Alicia");
+    assert_eq!(events[0].old_record.as_ref().unwrap().player_name, "Bob");
+}
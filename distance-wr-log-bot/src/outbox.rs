@@ -0,0 +1,250 @@
+use crate::discord::{self, DiscordWebhook};
+use crate::domain::{self, ChangelistEvent, Notification};
+use crate::persistence::Persistence;
+use anyhow::Result;
+use backoff::ExponentialBackoff;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use reqwest::StatusCode;
+use std::time::Duration;
+
+/// How long a delivery is retried during one run before it's left in the outbox for the next one.
+const MAX_RETRY_DURATION: Duration = Duration::from_secs(2 * 60);
+
+/// Creates a notification for every target that wants the event.
+pub fn notifications_for(
+    webhooks: &[DiscordWebhook],
+    events: &[ChangelistEvent],
+    now: DateTime<Utc>,
+) -> Vec<Notification> {
+    events
+        .iter()
+        .flat_map(|event| {
+            webhooks
+                .iter()
+                .filter(|webhook| webhook.matches(event))
+                .map(move |webhook| Notification {
+                    id: notification_id(&webhook.name, event),
+                    target: webhook.name.clone(),
+                    event: event.clone(),
+                    created_at: now,
+                })
+        })
+        .collect()
+}
+
+/// Identifies the delivery of an event to a target. Stable across runs, so an event that's found
+/// again isn't enqueued twice.
+pub fn notification_id(target: &str, event: &ChangelistEvent) -> String {
+    format!("{target}/{}", domain::guid(event))
+}
+
+/// A failed delivery, and whether the message may have been posted anyway.
+#[derive(Debug)]
+struct DeliveryError {
+    error: reqwest::Error,
+    maybe_delivered: bool,
+}
+
+/// Delivers the notifications in the outbox, removing them once they've been delivered.
+///
+/// Each message to a target carries up to [`discord::MAX_EMBEDS_PER_MESSAGE`] notifications. Failed
+/// deliveries are retried with exponential backoff, and whatever still fails stays in the outbox
+/// for the next run. Notifications for a target are delivered in order, so a failure holds back the
+/// rest of that target's notifications. Discord can't tell a retried message from a new one, so a
+/// delivery that may have been posted is never retried, and is dropped from the outbox instead.
+pub async fn deliver(webhooks: &[DiscordWebhook], persistence: &dyn Persistence) -> Result<()> {
+    deliver_with_backoff(webhooks, persistence, || ExponentialBackoff {
+        max_elapsed_time: Some(MAX_RETRY_DURATION),
+        ..Default::default()
+    })
+    .await
+}
+
+async fn deliver_with_backoff(
+    webhooks: &[DiscordWebhook],
+    persistence: &dyn Persistence,
+    backoff: impl Fn() -> ExponentialBackoff,
+) -> Result<()> {
+    let outbox = persistence.load_outbox()?;
+    if outbox.is_empty() {
+        return Ok(());
+    }
+    info!("Delivering {} notifications", outbox.len());
+
+    let (known, unknown): (Vec<_>, Vec<_>) = outbox.into_iter().partition(|notification| {
+        webhooks
+            .iter()
+            .any(|webhook| webhook.name == notification.target)
+    });
+    if !unknown.is_empty() {
+        warn!(
+            "Dropping {} notifications for targets that are no longer configured",
+            unknown.len()
+        );
+        persistence.remove_from_outbox(&unknown.into_iter().map(|x| x.id).collect::<Vec<_>>())?;
+    }
+
+    let client = reqwest::Client::new();
+    for webhook in webhooks {
+        let pending: Vec<_> = known.iter().filter(|x| x.target == webhook.name).collect();
+        for batch in pending.chunks(discord::MAX_EMBEDS_PER_MESSAGE) {
+            let events: Vec<_> = batch.iter().map(|x| &x.event).collect();
+            let result = backoff::future::retry(backoff(), || async {
+                discord::post(&client, webhook, &events)
+                    .await
+                    .map_err(classify_error)
+            })
+            .await;
+
+            match result {
+                Ok(()) => {}
+                Err(e) if e.maybe_delivered => warn!(
+                    "Delivering {} notifications to Discord webhook '{}' failed after the request \
+                     was sent, so they may have been posted; not retrying them: {}",
+                    batch.len(),
+                    webhook.name,
+                    e.error
+                ),
+                Err(e) => {
+                    warn!(
+                        "Couldn't deliver {} notifications to Discord webhook '{}', will retry on \
+                         the next run: {}",
+                        batch.len(),
+                        webhook.name,
+                        e.error
+                    );
+                    break;
+                }
+            }
+            persistence
+                .remove_from_outbox(&batch.iter().map(|x| x.id.clone()).collect::<Vec<_>>())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Only retries failures that certainly didn't post the message: connection failures, rate
+/// limiting and Discord being unavailable. Other client errors won't go away by retrying, and
+/// other failures, like timeouts and server errors, happen after the request was sent, so the
+/// message may have been posted.
+fn classify_error(error: reqwest::Error) -> backoff::Error<DeliveryError> {
+    let (retry, maybe_delivered) = match error.status() {
+        Some(StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE) => (true, false),
+        Some(status) if status.is_client_error() => (false, false),
+        None if error.is_connect() => (true, false),
+        _ => (false, true),
+    };
+    let error = DeliveryError {
+        error,
+        maybe_delivered,
+    };
+
+    if retry {
+        backoff::Error::transient(error)
+    } else {
+        backoff::Error::permanent(error)
+    }
+}
+
+#[tokio::test]
+async fn test_deliver() {
+    use crate::domain::ChangelistEventKind;
+    use crate::domain::ChangelistRecord;
+    use crate::in_memory_persistence::InMemoryPersistence;
+    use axum::Json;
+    use distance_util::LeaderboardGameMode;
+    use std::sync::{Arc, Mutex};
+
+    // `/` is unavailable for the first request, which is retried. `/gateway` fails after the
+    // request was sent, which isn't.
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let gateway_requests = Arc::new(Mutex::new(0));
+    let app = axum::Router::new()
+        .route(
+            "/",
+            axum::routing::post({
+                let received = received.clone();
+                move |Json(body): Json<serde_json::Value>| async move {
+                    let mut received = received.lock().unwrap();
+                    received.push(body["embeds"].as_array().unwrap().len());
+                    if received.len() == 1 {
+                        StatusCode::SERVICE_UNAVAILABLE
+                    } else {
+                        StatusCode::NO_CONTENT
+                    }
+                }
+            }),
+        )
+        .route(
+            "/gateway",
+            axum::routing::post({
+                let gateway_requests = gateway_requests.clone();
+                move || async move {
+                    *gateway_requests.lock().unwrap() += 1;
+                    StatusCode::BAD_GATEWAY
+                }
+            }),
+        );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let webhook = |name: &str, url: String| DiscordWebhook {
+        name: name.into(),
+        url,
+        modes: Vec::new(),
+        levels: Vec::new(),
+        kinds: Vec::new(),
+    };
+    let webhooks = [
+        webhook("test", url.clone()),
+        webhook("gateway", format!("{url}gateway")),
+    ];
+    let events: Vec<_> = (0..12)
+        .map(|score| ChangelistEvent {
+            kind: ChangelistEventKind::NewRecord,
+            leaderboard_name: Some("level_1_stable".into()),
+            map_name: "Level".into(),
+            mode: LeaderboardGameMode::Sprint,
+            map_author: None,
+            map_preview: None,
+            workshop_item_id: None,
+            steam_id_author: None,
+            new_record: Some(ChangelistRecord {
+                steam_id: 1,
+                player_name: "Player".into(),
+                score,
+            }),
+            old_record: None,
+            rank: None,
+            fetch_time: Utc::now(),
+        })
+        .collect();
+    let mut notifications = notifications_for(&webhooks, &events, Utc::now());
+    notifications.extend(notifications_for(
+        &[webhook("gone", url)],
+        &events[..1],
+        Utc::now(),
+    ));
+
+    let persistence = InMemoryPersistence::default();
+    persistence
+        .append_changelist(&events, &notifications)
+        .unwrap();
+    // Enqueueing the same notification again is a no-op
+    persistence.append_changelist(&[], &notifications).unwrap();
+    assert_eq!(persistence.load_outbox().unwrap().len(), 25);
+
+    deliver_with_backoff(&webhooks, &persistence, || ExponentialBackoff {
+        initial_interval: Duration::from_millis(1),
+        max_elapsed_time: Some(Duration::from_secs(10)),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    assert!(persistence.load_outbox().unwrap().is_empty());
+    assert_eq!(*received.lock().unwrap(), [10, 10, 2]);
+    assert_eq!(*gateway_requests.lock().unwrap(), 2);
+}
@@ -0,0 +1,69 @@
+use crate::domain::{ChangelistEvent, FetchState, LevelInfo, Notification};
+use crate::name_cache::NameCache;
+use crate::name_history::NameHistory;
+use crate::record_history::RecordHistory;
+use anyhow::Result;
+use thiserror::Error;
+
+/// A store for the bot's query results, changelist, record history, fetch state, and player names.
+///
+/// The query results are the latest snapshot of every level, replaced wholesale on each run, while
+/// the changelist, record history, and name history only ever grow.
+pub trait Persistence {
+    fn load_query_results(&self) -> Result<Vec<LevelInfo>, LoadError>;
+
+    fn save_query_results(&self, query_results: &[LevelInfo]) -> Result<()>;
+
+    fn load_changelist(&self) -> Result<Vec<ChangelistEvent>, LoadError>;
+
+    /// Appends events to the end of the changelist, creating it if it doesn't exist yet, and adds
+    /// the notifications about them to the outbox, all in one atomic write. Notifications already
+    /// in the outbox are skipped.
+    fn append_changelist(
+        &self,
+        events: &[ChangelistEvent],
+        notifications: &[Notification],
+    ) -> Result<()>;
+
+    /// Replaces the whole changelist, discarding any legacy changelist. Only used for migrations
+    /// and replays.
+    fn replace_changelist(&self, events: &[ChangelistEvent]) -> Result<()>;
+
+    /// Loads the notifications that haven't been delivered yet, oldest first.
+    fn load_outbox(&self) -> Result<Vec<Notification>>;
+
+    /// Removes delivered notifications from the outbox.
+    fn remove_from_outbox(&self, ids: &[String]) -> Result<()>;
+
+    fn load_record_history(&self) -> Result<RecordHistory, LoadError>;
+
+    fn save_record_history(&self, record_history: &RecordHistory) -> Result<()>;
+
+    fn load_fetch_state(&self) -> Result<FetchState, LoadError>;
+
+    fn save_fetch_state(&self, fetch_state: &FetchState) -> Result<()>;
+
+    fn load_name_cache(&self) -> Result<NameCache, LoadError>;
+
+    fn save_name_cache(&self, name_cache: &NameCache) -> Result<()>;
+
+    fn load_name_history(&self) -> Result<NameHistory, LoadError>;
+
+    fn save_name_history(&self, name_history: &NameHistory) -> Result<()>;
+
+    /// Changes whenever the stored data does, so readers can tell whether what they loaded is still
+    /// current. Only meaningful compared to other versions from the same instance.
+    fn data_version(&self) -> Result<u64>;
+}
+
+#[derive(Error, Debug)]
+pub enum LoadError {
+    #[error("The requested item does not exist.")]
+    DoesNotExist,
+
+    #[error("Only a legacy changelist exists, which has to be migrated first.")]
+    NeedsMigration,
+
+    #[error("{0}")]
+    Other(#[from] anyhow::Error),
+}
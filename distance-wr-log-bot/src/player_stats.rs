@@ -0,0 +1,395 @@
+use crate::domain::{ChangelistEvent, ChangelistEventKind, LevelInfo};
+use crate::file_json_persistence;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use distance_util::LeaderboardGameMode;
+use serde_derive::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Per-mode counts of records.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ModeCounts {
+    pub sprint: usize,
+    pub challenge: usize,
+    pub stunt: usize,
+}
+
+impl ModeCounts {
+    pub fn add(&mut self, mode: LeaderboardGameMode) {
+        match mode {
+            LeaderboardGameMode::Sprint => self.sprint += 1,
+            LeaderboardGameMode::Challenge => self.challenge += 1,
+            LeaderboardGameMode::Stunt => self.stunt += 1,
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.sprint + self.challenge + self.stunt
+    }
+}
+
+/// Everything about a player's world records, as written to `players/<steam id>.json`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlayerProfile {
+    pub steam_id: u64,
+    pub player_name: Option<String>,
+    /// The records the player holds in the current snapshot.
+    pub current_records: ModeCounts,
+    /// How many times the player set a record, including improving their own.
+    pub records_set: usize,
+    /// How many times someone else took a record from the player.
+    pub records_lost: usize,
+    /// Who took the player's records, most often first.
+    pub lost_to: Vec<Rival>,
+    pub longest_reign: Option<Reign>,
+    pub first_record: Option<DateTime<Utc>>,
+    pub last_record: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Rival {
+    pub steam_id: u64,
+    pub player_name: String,
+    pub count: usize,
+}
+
+/// An uninterrupted stretch of a player holding a level's record.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Reign {
+    pub leaderboard_name: Option<String>,
+    pub map_name: String,
+    pub mode: LeaderboardGameMode,
+    /// The player's best score during the reign.
+    pub score: i32,
+    pub since: DateTime<Utc>,
+    /// `None` if the player still holds the record.
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl Reign {
+    fn duration(&self, now: DateTime<Utc>) -> chrono::Duration {
+        self.until.unwrap_or(now) - self.since
+    }
+}
+
+/// A line of the player index in `players.json`. The players' ranks are in `rankings.json`, which
+/// [`crate::wr_rankings`] writes.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlayerSummary {
+    pub steam_id: u64,
+    pub player_name: Option<String>,
+    pub current_records: ModeCounts,
+    pub current_record_count: usize,
+    pub records_set: usize,
+    pub records_lost: usize,
+}
+
+/// Computes the profile of every player who ever set a record or holds one, ordered by how many
+/// records they currently hold, then by how many they ever set.
+pub fn compute_profiles(
+    changelist: &[ChangelistEvent],
+    query_results: &[LevelInfo],
+    now: DateTime<Utc>,
+) -> Vec<PlayerProfile> {
+    let mut profiles: HashMap<u64, PlayerProfile> = HashMap::new();
+    let mut lost_to: HashMap<(u64, u64), usize> = HashMap::new();
+    let mut rival_names: HashMap<u64, String> = HashMap::new();
+    // The open reign on each level, keyed by leaderboard name, or by map name and mode for events
+    // from before leaderboard names were stored
+    let mut reigns: HashMap<String, Reign> = HashMap::new();
+    let mut holders: HashMap<String, u64> = HashMap::new();
+    let mut finished: Vec<(u64, Reign)> = Vec::new();
+
+    for event in changelist.iter().filter(|x| !x.kind.is_rank_event()) {
+        let level = match &event.leaderboard_name {
+            Some(leaderboard_name) => leaderboard_name.clone(),
+            None => format!("{} ({})", event.map_name, event.mode.name()),
+        };
+
+        if let Some(new) = &event.new_record {
+            rival_names.insert(new.steam_id, new.player_name.clone());
+        }
+        if matches!(
+            event.kind,
+            ChangelistEventKind::NewRecord | ChangelistEventKind::FirstRecord
+        ) {
+            if let Some(new) = &event.new_record {
+                let profile = entry(&mut profiles, new.steam_id);
+                profile.player_name = Some(new.player_name.clone());
+                profile.records_set += 1;
+                profile.first_record.get_or_insert(event.fetch_time);
+                profile.last_record = Some(event.fetch_time);
+
+                if let Some(old) = &event.old_record {
+                    if old.steam_id != new.steam_id {
+                        entry(&mut profiles, old.steam_id).records_lost += 1;
+                        *lost_to.entry((old.steam_id, new.steam_id)).or_default() += 1;
+                    }
+                }
+            }
+        }
+
+        let new_holder = match event.kind {
+            ChangelistEventKind::LevelPublished => continue,
+            ChangelistEventKind::LevelRemoved => None,
+            _ => event.new_record.as_ref(),
+        };
+        if let Some(&previous_holder) = holders.get(&level) {
+            if Some(previous_holder) != new_holder.map(|x| x.steam_id) {
+                let mut reign = reigns.remove(&level).unwrap();
+                reign.until = Some(event.fetch_time);
+                finished.push((previous_holder, reign));
+                holders.remove(&level);
+            }
+        }
+        if let Some(new) = new_holder {
+            holders.insert(level.clone(), new.steam_id);
+            let reign = reigns.entry(level).or_insert_with(|| Reign {
+                leaderboard_name: event.leaderboard_name.clone(),
+                map_name: event.map_name.clone(),
+                mode: event.mode,
+                score: new.score,
+                since: event.fetch_time,
+                until: None,
+            });
+            reign.score = new.score;
+        }
+    }
+
+    let open = reigns
+        .into_iter()
+        .map(|(level, reign)| (holders[&level], reign));
+    for (steam_id, reign) in finished.into_iter().chain(open) {
+        let profile = entry(&mut profiles, steam_id);
+        let longer = profile
+            .longest_reign
+            .as_ref()
+            .is_none_or(|longest| reign.duration(now) > longest.duration(now));
+        if longer {
+            profile.longest_reign = Some(reign);
+        }
+    }
+
+    for level in query_results {
+        if let Some(top) = level.leaderboard_response.entries.first() {
+            let profile = entry(&mut profiles, top.steam_id);
+            profile.current_records.add(level.mode);
+            if top.player_name.is_some() {
+                profile.player_name.clone_from(&top.player_name);
+            }
+        }
+    }
+
+    for ((steam_id, rival), count) in lost_to {
+        entry(&mut profiles, steam_id).lost_to.push(Rival {
+            steam_id: rival,
+            player_name: rival_names.get(&rival).cloned().unwrap_or_default(),
+            count,
+        });
+    }
+
+    let mut profiles: Vec<_> = profiles.into_values().collect();
+    for profile in &mut profiles {
+        profile
+            .lost_to
+            .sort_by_key(|rival| (std::cmp::Reverse(rival.count), rival.steam_id));
+    }
+    profiles.sort_by_key(|profile| {
+        (
+            std::cmp::Reverse(profile.current_records.total()),
+            std::cmp::Reverse(profile.records_set),
+            profile.steam_id,
+        )
+    });
+
+    profiles
+}
+
+/// The profile of a player, created empty if they don't have one yet.
+fn entry(profiles: &mut HashMap<u64, PlayerProfile>, steam_id: u64) -> &mut PlayerProfile {
+    profiles.entry(steam_id).or_insert_with(|| PlayerProfile {
+        steam_id,
+        player_name: None,
+        current_records: ModeCounts::default(),
+        records_set: 0,
+        records_lost: 0,
+        lost_to: Vec::new(),
+        longest_reign: None,
+        first_record: None,
+        last_record: None,
+    })
+}
+
+/// Writes every profile to `players/<steam id>.json` in `dir`, and an index of them to
+/// `players.json`.
+/// Profile files are only rewritten when they changed.
+pub fn write_profiles(dir: &Path, profiles: &[PlayerProfile]) -> Result<()> {
+    let players_dir = dir.join("players");
+    fs::create_dir_all(&players_dir)?;
+
+    for profile in profiles {
+        let path = players_dir.join(format!("{}.json", profile.steam_id));
+        let data = serde_json::to_vec(profile)?;
+        if fs::read(&path).is_ok_and(|existing| existing == data) {
+            continue;
+        }
+
+        file_json_persistence::write_atomically(&data, &path)?;
+    }
+
+    let summaries: Vec<_> = profiles
+        .iter()
+        .map(|profile| PlayerSummary {
+            steam_id: profile.steam_id,
+            player_name: profile.player_name.clone(),
+            current_records: profile.current_records,
+            current_record_count: profile.current_records.total(),
+            records_set: profile.records_set,
+            records_lost: profile.records_lost,
+        })
+        .collect();
+
+    file_json_persistence::write_atomically(
+        &serde_json::to_vec(&summaries)?,
+        &dir.join("players.json"),
+    )
+}
+
+#[test]
+fn test_compute_profiles() {
+    use crate::domain::ChangelistRecord;
+    use crate::steamworks::{LeaderboardEntry, LeaderboardResponse};
+
+    let start = Utc::now();
+    let time = |hours| start + chrono::Duration::hours(hours);
+    let record = |steam_id, score| ChangelistRecord {
+        steam_id,
+        player_name: format!("Player {steam_id}"),
+        score,
+    };
+    let event = |kind, level: &str, mode, new_record, old_record, hours| ChangelistEvent {
+        kind,
+        leaderboard_name: Some(level.to_owned()),
+        map_name: level.to_owned(),
+        mode,
+        map_author: None,
+        map_preview: None,
+        workshop_item_id: None,
+        steam_id_author: None,
+        new_record: Some(new_record),
+        old_record,
+        rank: None,
+        fetch_time: time(hours),
+    };
+    let level = |name: &str, mode, steam_id, score| LevelInfo {
+        name: name.to_owned(),
+        mode,
+        leaderboard_name: name.to_owned(),
+        workshop_response: None,
+        leaderboard_response: LeaderboardResponse {
+            entries: Box::new([LeaderboardEntry {
+                steam_id,
+                global_rank: 1,
+                score,
+                player_name: Some(format!("Player {steam_id}")),
+            }]),
+            range_end: 2,
+        },
+        timestamp: time(20),
+        missing_since: None,
+    };
+
+    let sprint = LeaderboardGameMode::Sprint;
+    let stunt = LeaderboardGameMode::Stunt;
+    let changelist = [
+        event(
+            ChangelistEventKind::FirstRecord,
+            "a",
+            sprint,
+            record(1, 300),
+            None,
+            1,
+        ),
+        event(
+            ChangelistEventKind::FirstRecord,
+            "b",
+            stunt,
+            record(1, 50),
+            None,
+            1,
+        ),
+        event(
+            ChangelistEventKind::NewRecord,
+            "a",
+            sprint,
+            record(2, 200),
+            Some(record(1, 300)),
+            2,
+        ),
+        event(
+            ChangelistEventKind::NewRecord,
+            "a",
+            sprint,
+            record(2, 150),
+            Some(record(2, 200)),
+            3,
+        ),
+        event(
+            ChangelistEventKind::NewRecord,
+            "a",
+            sprint,
+            record(1, 100),
+            Some(record(2, 150)),
+            10,
+        ),
+    ];
+    let query_results = [level("a", sprint, 1, 100), level("b", stunt, 1, 50)];
+
+    let profiles = compute_profiles(&changelist, &query_results, time(20));
+    assert_eq!(profiles.len(), 2);
+    let (first, second) = (&profiles[0], &profiles[1]);
+
+    assert_eq!(first.steam_id, 1);
+    assert_eq!(
+        first.current_records,
+        ModeCounts {
+            sprint: 1,
+            challenge: 0,
+            stunt: 1
+        }
+    );
+    assert_eq!((first.records_set, first.records_lost), (3, 1));
+    assert_eq!(
+        first.lost_to,
+        [Rival {
+            steam_id: 2,
+            player_name: "Player 2".into(),
+            count: 1
+        }]
+    );
+    let longest = first.longest_reign.as_ref().unwrap();
+    assert_eq!((longest.map_name.as_str(), longest.until), ("b", None));
+    assert_eq!(
+        (first.first_record, first.last_record),
+        (Some(time(1)), Some(time(10)))
+    );
+
+    assert_eq!(second.steam_id, 2);
+    assert_eq!(second.current_records.total(), 0);
+    assert_eq!((second.records_set, second.records_lost), (2, 1));
+    let longest = second.longest_reign.as_ref().unwrap();
+    assert_eq!(
+        (longest.score, longest.since, longest.until),
+        (150, time(2), Some(time(10)))
+    );
+
+    let dir = tempfile::tempdir().unwrap();
+    write_profiles(dir.path(), &profiles).unwrap();
+    assert!(dir.path().join("players").join("2.json").exists());
+    let index: serde_json::Value =
+        serde_json::from_slice(&fs::read(dir.path().join("players.json")).unwrap()).unwrap();
+    assert_eq!(index[0]["steam_id"], 1);
+    assert_eq!(index[0]["current_record_count"], 2);
+}
@@ -0,0 +1,152 @@
+use crate::LevelInfo;
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Every #1 ever observed on each leaderboard, keyed by leaderboard name.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RecordHistory {
+    leaderboards: BTreeMap<String, Vec<RecordHolding>>,
+}
+
+/// A record as observed across one or more consecutive runs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordHolding {
+    pub steam_id: u64,
+    pub score: i32,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+impl RecordHistory {
+    pub fn from_holdings(holdings: impl IntoIterator<Item = (String, Vec<RecordHolding>)>) -> Self {
+        RecordHistory {
+            leaderboards: holdings.into_iter().collect(),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[RecordHolding])> {
+        self.leaderboards
+            .iter()
+            .map(|(name, holdings)| (name.as_str(), holdings.as_slice()))
+    }
+
+    /// The records observed on a leaderboard, oldest first.
+    pub fn get(&self, leaderboard_name: &str) -> &[RecordHolding] {
+        self.leaderboards
+            .get(leaderboard_name)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Records the current #1 of each level. Returns the names of the leaderboards whose #1
+    /// changed.
+    ///
+    /// Levels whose data is older than what was already observed (e.g. because it was carried over
+    /// from a previous run after Steam failed to return it) are ignored.
+    pub fn observe<'a>(&mut self, level_infos: &'a [LevelInfo]) -> Vec<&'a str> {
+        let mut changed = Vec::new();
+        for level_info in level_infos {
+            let Some(top) = level_info.leaderboard_response.entries.first() else {
+                continue;
+            };
+
+            let holdings = self
+                .leaderboards
+                .entry(level_info.leaderboard_name.clone())
+                .or_default();
+            if let Some(last) = holdings.last_mut() {
+                if level_info.timestamp <= last.last_seen {
+                    continue;
+                }
+
+                if last.steam_id == top.steam_id && last.score == top.score {
+                    last.last_seen = level_info.timestamp;
+                    continue;
+                }
+            }
+
+            holdings.push(RecordHolding {
+                steam_id: top.steam_id,
+                score: top.score,
+                first_seen: level_info.timestamp,
+                last_seen: level_info.timestamp,
+            });
+            changed.push(level_info.leaderboard_name.as_str());
+        }
+
+        changed
+    }
+
+    /// The record that stood on the leaderboard at the given time, as far as we know.
+    pub fn holder_at(&self, leaderboard_name: &str, time: DateTime<Utc>) -> Option<&RecordHolding> {
+        self.get(leaderboard_name)
+            .iter()
+            .take_while(|holding| holding.first_seen <= time)
+            .last()
+    }
+
+    /// How long each record on the leaderboard stood, oldest first. A record stands until the
+    /// next one is first seen; the current record has stood until it was last seen.
+    pub fn reign_durations(
+        &self,
+        leaderboard_name: &str,
+    ) -> impl DoubleEndedIterator<Item = (&RecordHolding, chrono::Duration)> {
+        let holdings = self.get(leaderboard_name);
+        holdings.iter().enumerate().map(move |(i, holding)| {
+            let end = holdings
+                .get(i + 1)
+                .map(|next| next.first_seen)
+                .unwrap_or(holding.last_seen);
+
+            (holding, end - holding.first_seen)
+        })
+    }
+}
+
+#[test]
+fn test_record_history() {
+    use crate::steamworks::{LeaderboardEntry, LeaderboardResponse};
+    use chrono::TimeZone;
+    use distance_util::LeaderboardGameMode;
+
+    let day = |d| Utc.with_ymd_and_hms(2024, 1, d, 0, 0, 0).unwrap();
+    let level = |steam_id, score, timestamp| LevelInfo {
+        name: "Test Level".into(),
+        mode: LeaderboardGameMode::Sprint,
+        leaderboard_name: "test_level".into(),
+        workshop_response: None,
+        leaderboard_response: LeaderboardResponse {
+            entries: Box::new([LeaderboardEntry {
+                steam_id,
+                global_rank: 1,
+                score,
+                player_name: None,
+            }]),
+            range_end: 2,
+        },
+        timestamp,
+        missing_since: None,
+    };
+
+    let mut history = RecordHistory::default();
+    assert_eq!(history.observe(&[level(1, 10_000, day(1))]), ["test_level"]);
+    assert!(history.observe(&[level(1, 10_000, day(2))]).is_empty());
+    assert_eq!(history.observe(&[level(2, 9_000, day(5))]), ["test_level"]);
+    assert!(history.observe(&[level(1, 10_000, day(4))]).is_empty());
+    history.observe(&[level(2, 9_000, day(6))]);
+
+    assert_eq!(history.get("test_level").len(), 2);
+    assert_eq!(history.holder_at("test_level", day(3)).unwrap().steam_id, 1);
+    assert_eq!(history.holder_at("test_level", day(9)).unwrap().steam_id, 2);
+    assert!(history
+        .holder_at("test_level", day(1) - chrono::Duration::hours(1))
+        .is_none());
+
+    let durations: Vec<_> = history
+        .reign_durations("test_level")
+        .map(|(holding, duration)| (holding.steam_id, duration.num_days()))
+        .collect();
+    assert_eq!(durations, [(1, 4), (2, 1)]);
+}
@@ -0,0 +1,161 @@
+use crate::domain::{ChangelistEvent, ChangelistEventKind, LevelInfo};
+use crate::steamworks::WorkshopResponse;
+use chrono::{DateTime, Utc};
+use distance_util::LeaderboardGameMode;
+use distance_wr_log_config::Fetch as FetchConfig;
+use std::collections::HashMap;
+
+/// How much each record change within the hot period raises a leaderboard's priority, relative to
+/// a workshop vote score of 1.
+const ACTIVITY_WEIGHT: f64 = 1.0;
+
+/// A leaderboard an update is going to poll.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub name: String,
+    pub mode: LeaderboardGameMode,
+    pub leaderboard_name: String,
+    /// `None` for official levels.
+    pub workshop_response: Option<WorkshopResponse>,
+}
+
+/// Sorts the candidates so the leaderboards most likely to have changed are polled first.
+///
+/// A leaderboard's priority is the time since it was last polled, multiplied by a weight that
+/// grows with its recent record activity and its workshop vote score. Since the time since the last
+/// poll keeps growing, a quiet leaderboard eventually outranks a busy one that was just polled, so
+/// no level starves. Leaderboards that were never polled come first.
+pub fn prioritize(
+    candidates: &mut Vec<Candidate>,
+    old_level_infos: &[LevelInfo],
+    changelist: &[ChangelistEvent],
+    config: &FetchConfig,
+    now: DateTime<Utc>,
+) {
+    let age = |time: DateTime<Utc>| (now - time).to_std().unwrap_or_default();
+
+    let last_polled: HashMap<&str, DateTime<Utc>> = old_level_infos
+        .iter()
+        .map(|level| (level.leaderboard_name.as_str(), level.timestamp))
+        .collect();
+    let mut activity: HashMap<&str, u32> = HashMap::new();
+    for event in changelist {
+        let is_record_change = matches!(
+            event.kind,
+            ChangelistEventKind::NewRecord
+                | ChangelistEventKind::FirstRecord
+                | ChangelistEventKind::RecordRemoved
+        );
+        if let (true, Some(leaderboard_name)) = (is_record_change, &event.leaderboard_name) {
+            if age(event.fetch_time) < config.hot_period {
+                *activity.entry(leaderboard_name).or_default() += 1;
+            }
+        }
+    }
+
+    let priority = |candidate: &Candidate| {
+        let Some(&last_polled) = last_polled.get(candidate.leaderboard_name.as_str()) else {
+            return f64::INFINITY;
+        };
+        let staleness = age(last_polled).as_secs_f64();
+        let score = candidate
+            .workshop_response
+            .as_ref()
+            .map_or(1.0, |workshop| f64::from(workshop.score));
+        let activity = activity
+            .get(candidate.leaderboard_name.as_str())
+            .copied()
+            .unwrap_or_default();
+
+        staleness * (1.0 + score + ACTIVITY_WEIGHT * f64::from(activity))
+    };
+
+    // The priorities are computed once up front, since the history lookups add up across thousands
+    // of workshop leaderboards
+    let mut prioritized: Vec<_> = candidates
+        .drain(..)
+        .map(|candidate| (priority(&candidate), candidate))
+        .collect();
+    prioritized.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+    candidates.extend(prioritized.into_iter().map(|(_, candidate)| candidate));
+}
+
+#[test]
+fn test_prioritize() {
+    use crate::steamworks::LeaderboardResponse;
+
+    let now = Utc::now();
+    let ago = |minutes| now - chrono::Duration::minutes(minutes);
+    let workshop_response = |score| WorkshopResponse {
+        published_file_id: 1,
+        steam_id_owner: 1,
+        file_name: String::new(),
+        title: String::new(),
+        score,
+        tags: Box::new([]),
+        author_name: None,
+        preview_url: String::new(),
+    };
+    let candidate = |leaderboard_name: &str, score| Candidate {
+        name: String::new(),
+        mode: LeaderboardGameMode::Sprint,
+        leaderboard_name: leaderboard_name.to_owned(),
+        workshop_response: Some(workshop_response(score)),
+    };
+    let polled = |leaderboard_name: &str, minutes| LevelInfo {
+        name: String::new(),
+        mode: LeaderboardGameMode::Sprint,
+        leaderboard_name: leaderboard_name.to_owned(),
+        workshop_response: None,
+        leaderboard_response: LeaderboardResponse {
+            entries: Box::new([]),
+            range_end: 2,
+        },
+        timestamp: ago(minutes),
+        missing_since: None,
+    };
+    let record_change = |leaderboard_name: &str| ChangelistEvent {
+        kind: ChangelistEventKind::NewRecord,
+        leaderboard_name: Some(leaderboard_name.to_owned()),
+        map_name: String::new(),
+        mode: LeaderboardGameMode::Sprint,
+        map_author: None,
+        map_preview: None,
+        workshop_item_id: None,
+        steam_id_author: None,
+        new_record: None,
+        old_record: None,
+        rank: None,
+        fetch_time: ago(60),
+    };
+
+    let mut candidates = vec![
+        candidate("quiet", 0.0),
+        candidate("popular", 1.0),
+        candidate("busy", 0.0),
+        candidate("new", 0.0),
+        candidate("neglected", 0.0),
+    ];
+    let old_level_infos = [
+        polled("quiet", 10),
+        polled("popular", 10),
+        polled("busy", 10),
+        polled("neglected", 100),
+    ];
+    let changelist = [record_change("busy"), record_change("busy")];
+    prioritize(
+        &mut candidates,
+        &old_level_infos,
+        &changelist,
+        &FetchConfig::default(),
+        now,
+    );
+
+    assert_eq!(
+        candidates
+            .iter()
+            .map(|x| x.leaderboard_name.as_str())
+            .collect::<Vec<_>>(),
+        ["new", "neglected", "busy", "popular", "quiet"]
+    );
+}
@@ -0,0 +1,181 @@
+use crate::domain::LevelInfo;
+use crate::file_json_persistence;
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use distance_wr_log_config::Snapshots as SnapshotsConfig;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+/// Archived snapshots are named after the time of the update, in a format that sorts
+/// chronologically.
+const FILE_NAME_FORMAT: &str = "%Y%m%dT%H%M%S%.9fZ";
+
+const EXTENSION: &str = ".json.zst";
+
+const COMPRESSION_LEVEL: i32 = 9;
+
+/// A directory of zstd-compressed query results, one per update.
+#[derive(Debug, Clone)]
+pub struct SnapshotArchive {
+    dir: PathBuf,
+    retention: Option<SnapshotsConfig>,
+}
+
+impl SnapshotArchive {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        SnapshotArchive {
+            dir: dir.into(),
+            retention: None,
+        }
+    }
+
+    /// Makes [`prune`](Self::prune) thin out the archive according to the retention policy in the
+    /// config.
+    pub fn with_retention(mut self, config: SnapshotsConfig) -> Self {
+        self.retention = Some(config);
+        self
+    }
+
+    /// Archives the query results of the update that ran at `time`.
+    pub fn save(&self, snapshot: &[LevelInfo], time: DateTime<Utc>) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let data = zstd::encode_all(serde_json::to_vec(snapshot)?.as_slice(), COMPRESSION_LEVEL)?;
+
+        file_json_persistence::write_atomically(&data, &self.path(time))
+    }
+
+    /// The times of the archived snapshots, oldest first.
+    pub fn list(&self) -> Result<Vec<DateTime<Utc>>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut times = Vec::new();
+        for entry in entries {
+            let file_name = entry?.file_name();
+            let time = file_name
+                .to_str()
+                .and_then(|x| x.strip_suffix(EXTENSION))
+                .and_then(|x| NaiveDateTime::parse_from_str(x, FILE_NAME_FORMAT).ok());
+            if let Some(time) = time {
+                times.push(time.and_utc());
+            }
+        }
+        times.sort_unstable();
+
+        Ok(times)
+    }
+
+    /// Deletes the snapshots the retention policy doesn't keep, returning how many were deleted.
+    pub fn prune(&self, now: DateTime<Utc>) -> Result<usize> {
+        let Some(retention) = &self.retention else {
+            return Ok(0);
+        };
+
+        let doomed = snapshots_to_prune(&self.list()?, retention, now);
+        for &time in &doomed {
+            fs::remove_file(self.path(time))?;
+        }
+
+        Ok(doomed.len())
+    }
+
+    pub fn load(&self, time: DateTime<Utc>) -> Result<Vec<LevelInfo>> {
+        read_snapshot(&self.path(time))
+    }
+
+    fn path(&self, time: DateTime<Utc>) -> PathBuf {
+        self.dir
+            .join(format!("{}{EXTENSION}", time.format(FILE_NAME_FORMAT)))
+    }
+}
+
+/// The period a thinned out snapshot represents.
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum Bucket {
+    Hour(i64),
+    Day(NaiveDate),
+}
+
+/// Picks the snapshots to delete out of `times`, which are sorted oldest first. Only the oldest
+/// snapshot in each hour or day is kept, depending on its age.
+fn snapshots_to_prune(
+    times: &[DateTime<Utc>],
+    retention: &SnapshotsConfig,
+    now: DateTime<Utc>,
+) -> Vec<DateTime<Utc>> {
+    let mut kept = HashSet::new();
+    times
+        .iter()
+        .copied()
+        .filter(|&time| {
+            let age = (now - time).to_std().unwrap_or_default();
+            let bucket = if age < retention.keep_all {
+                return false;
+            } else if age < retention.keep_hourly {
+                Bucket::Hour(time.timestamp().div_euclid(60 * 60))
+            } else if retention.keep_daily.is_none_or(|x| age < x) {
+                Bucket::Day(time.date_naive())
+            } else {
+                return true;
+            };
+
+            !kept.insert(bucket)
+        })
+        .collect()
+}
+
+/// Reads a snapshot, either an archived one or a plain query results file.
+pub fn read_snapshot(path: &Path) -> Result<Vec<LevelInfo>> {
+    let file = File::open(path).with_context(|| format!("couldn't open {}", path.display()))?;
+    let snapshot = if path.extension().is_some_and(|x| x == "zst") {
+        serde_json::from_reader(zstd::Decoder::new(file)?)
+    } else {
+        serde_json::from_reader(BufReader::new(file))
+    };
+
+    snapshot.with_context(|| format!("couldn't parse {}", path.display()))
+}
+
+#[test]
+fn test_snapshots_to_prune() {
+    use chrono::{Duration, TimeZone};
+
+    let now = Utc.with_ymd_and_hms(2024, 6, 30, 12, 0, 0).unwrap();
+    let retention = SnapshotsConfig {
+        keep_daily: Some(std::time::Duration::from_secs(90 * 24 * 60 * 60)),
+        ..SnapshotsConfig::default()
+    };
+    let ago = |minutes| now - Duration::minutes(minutes);
+
+    let times = [
+        // Too old to keep at all
+        ago(100 * 24 * 60),
+        // The first of these is kept, as the day's snapshot
+        ago(40 * 24 * 60 + 10),
+        ago(40 * 24 * 60 + 5),
+        // Two different hours, with the first of each kept
+        ago(5 * 24 * 60 + 70),
+        ago(5 * 24 * 60 + 65),
+        ago(5 * 24 * 60 + 5),
+        // All kept
+        ago(10),
+        ago(5),
+    ];
+    assert_eq!(
+        snapshots_to_prune(&times, &retention, now),
+        [times[0], times[2], times[4]]
+    );
+
+    let dir = tempfile::tempdir().unwrap();
+    let archive = SnapshotArchive::new(dir.path()).with_retention(retention);
+    for time in times {
+        archive.save(&[], time).unwrap();
+    }
+    assert_eq!(archive.prune(now).unwrap(), 3);
+    assert_eq!(archive.list().unwrap().len(), 5);
+}
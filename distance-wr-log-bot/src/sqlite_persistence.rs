@@ -0,0 +1,1013 @@
+use crate::domain::{self, ChangelistEvent, ChangelistRecord, FetchState, LevelInfo, Notification};
+use crate::file_json_persistence;
+use crate::name_cache::{CachedName, NameCache};
+use crate::name_history::{NameHistory, NameSighting};
+use crate::persistence::{LoadError, Persistence};
+use crate::record_history::{RecordHistory, RecordHolding};
+use crate::steamworks::{LeaderboardEntry, LeaderboardResponse, WorkshopResponse};
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Each migration brings the database schema up by one version, tracked with `PRAGMA user_version`.
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS levels (
+        leaderboard_name TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        mode TEXT NOT NULL,
+        published_file_id INTEGER,
+        steam_id_owner INTEGER,
+        file_name TEXT,
+        title TEXT,
+        vote_score REAL,
+        tags TEXT,
+        preview_url TEXT,
+        timestamp TEXT NOT NULL,
+        missing_since TEXT,
+        leaderboard_range_end INTEGER NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS leaderboard_entries (
+        leaderboard_name TEXT NOT NULL REFERENCES levels (leaderboard_name) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        steam_id INTEGER NOT NULL,
+        global_rank INTEGER NOT NULL,
+        score INTEGER NOT NULL,
+        PRIMARY KEY (leaderboard_name, position)
+    );
+
+    -- Names that were only ever saved with the query results have no time, and are resolved again
+    CREATE TABLE IF NOT EXISTS player_names (
+        steam_id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        resolved_at TEXT
+    );
+
+    CREATE TABLE IF NOT EXISTS name_history (
+        steam_id INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        first_seen TEXT NOT NULL,
+        last_seen TEXT NOT NULL,
+        PRIMARY KEY (steam_id, first_seen)
+    );
+
+    CREATE TABLE IF NOT EXISTS changelist_events (
+        id INTEGER PRIMARY KEY,
+        kind TEXT NOT NULL,
+        leaderboard_name TEXT,
+        map_name TEXT NOT NULL,
+        mode TEXT NOT NULL,
+        map_author TEXT,
+        map_preview TEXT,
+        workshop_item_id INTEGER,
+        steam_id_author INTEGER,
+        new_steam_id INTEGER,
+        new_player_name TEXT,
+        new_score INTEGER,
+        old_steam_id INTEGER,
+        old_player_name TEXT,
+        old_score INTEGER,
+        rank INTEGER,
+        fetch_time TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS outbox (
+        position INTEGER PRIMARY KEY,
+        id TEXT NOT NULL UNIQUE,
+        target TEXT NOT NULL,
+        event TEXT NOT NULL,
+        created_at TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS record_history (
+        leaderboard_name TEXT NOT NULL,
+        steam_id INTEGER NOT NULL,
+        score INTEGER NOT NULL,
+        first_seen TEXT NOT NULL,
+        last_seen TEXT NOT NULL,
+        PRIMARY KEY (leaderboard_name, first_seen)
+    );
+"];
+
+/// How long to wait for another connection's write to finish before giving up.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+const QUERY_RESULTS_SAVED_KEY: &str = "query_results_saved_at";
+const CHANGELIST_SAVED_KEY: &str = "changelist_events_saved_at";
+const RECORD_HISTORY_SAVED_KEY: &str = "record_history_saved_at";
+/// Unlike the other keys, this one holds a JSON document rather than a timestamp.
+const FETCH_STATE_KEY: &str = "fetch_state";
+const NAME_CACHE_SAVED_KEY: &str = "name_cache_saved_at";
+const NAME_HISTORY_SAVED_KEY: &str = "name_history_saved_at";
+
+/// Stores the query results, changelist, and record history in a SQLite database, so each run only
+/// writes what changed instead of rewriting everything.
+///
+/// Since the frontend reads `changelist.json` directly, the changelist can additionally be exported
+/// to a JSON file every time it's saved.
+#[derive(Debug)]
+pub struct SqlitePersistence {
+    connection: Connection,
+    changelist_export_path: Option<PathBuf>,
+}
+
+impl SqlitePersistence {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let connection = Connection::open(path)
+            .with_context(|| format!("couldn't open SQLite database {}", path.display()))?;
+        connection.pragma_update(None, "foreign_keys", true)?;
+        // The HTTP API reads while updates write
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        migrate(&connection)?;
+
+        Ok(SqlitePersistence {
+            connection,
+            changelist_export_path: None,
+        })
+    }
+
+    /// Also write the whole changelist to the given JSON file every time it's saved.
+    pub fn with_changelist_export(mut self, path: impl Into<PathBuf>) -> Self {
+        self.changelist_export_path = Some(path.into());
+        self
+    }
+
+    fn read_query_results(&self) -> Result<Vec<LevelInfo>> {
+        let player_names = self.load_player_names()?;
+        let mut entries = self.load_leaderboard_entries(&player_names)?;
+
+        let mut statement = self.connection.prepare(
+            "SELECT leaderboard_name, name, mode, published_file_id, steam_id_owner, file_name,
+                        title, vote_score, tags, preview_url, timestamp, missing_since,
+                        leaderboard_range_end
+                 FROM levels
+                 ORDER BY leaderboard_name",
+        )?;
+        let level_infos = statement
+            .query_map([], |row| {
+                let leaderboard_name: String = row.get(0)?;
+                let published_file_id: Option<u64> = row.get(3)?;
+                let workshop_response = match published_file_id {
+                    Some(published_file_id) => {
+                        let steam_id_owner: u64 = row.get(4)?;
+                        let tags: String = row.get(8)?;
+                        Some(WorkshopResponse {
+                            published_file_id,
+                            steam_id_owner,
+                            file_name: row.get(5)?,
+                            title: row.get(6)?,
+                            score: row.get::<_, f64>(7)? as f32,
+                            tags: serde_json::from_str(&tags).map_err(|e| {
+                                rusqlite::Error::FromSqlConversionFailure(
+                                    8,
+                                    rusqlite::types::Type::Text,
+                                    e.into(),
+                                )
+                            })?,
+                            author_name: player_names.get(&steam_id_owner).cloned(),
+                            preview_url: row.get(9)?,
+                        })
+                    }
+                    None => None,
+                };
+
+                Ok(LevelInfo {
+                    name: row.get(1)?,
+                    mode: enum_from_sql(row, 2)?,
+                    leaderboard_response: LeaderboardResponse {
+                        entries: entries
+                            .remove(&leaderboard_name)
+                            .unwrap_or_default()
+                            .into_boxed_slice(),
+                        range_end: row.get(12)?,
+                    },
+                    leaderboard_name,
+                    workshop_response,
+                    timestamp: row.get(10)?,
+                    missing_since: row.get(11)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(level_infos)
+    }
+
+    /// Writes the levels that differ from the stored ones, and deletes the ones that are gone.
+    fn write_query_results(&self, query_results: &[LevelInfo]) -> Result<()> {
+        let mut stored: HashMap<String, serde_json::Value> = self
+            .read_query_results()?
+            .into_iter()
+            .map(|level_info| {
+                Ok((
+                    level_info.leaderboard_name.clone(),
+                    serde_json::to_value(level_info)?,
+                ))
+            })
+            .collect::<Result<_>>()?;
+        let mut player_names = self.load_player_names()?;
+
+        let tx = self.connection.unchecked_transaction()?;
+        {
+            let mut delete_entries =
+                tx.prepare("DELETE FROM leaderboard_entries WHERE leaderboard_name = ?1")?;
+            let mut delete_level = tx.prepare("DELETE FROM levels WHERE leaderboard_name = ?1")?;
+            let mut insert_level = tx.prepare(
+                "INSERT INTO levels (leaderboard_name, name, mode, published_file_id,
+                                     steam_id_owner, file_name, title, vote_score, tags,
+                                     preview_url, timestamp, missing_since, leaderboard_range_end)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            )?;
+            let mut insert_entry = tx.prepare(
+                "INSERT INTO leaderboard_entries (leaderboard_name, position, steam_id, global_rank,
+                                                  score)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            let mut upsert_name = tx.prepare(
+                "INSERT INTO player_names (steam_id, name) VALUES (?1, ?2)
+                 ON CONFLICT (steam_id) DO UPDATE SET name = excluded.name",
+            )?;
+
+            let mut upsert_name = |steam_id: u64, name: &String| -> Result<()> {
+                if player_names.get(&steam_id) != Some(name) {
+                    upsert_name.execute(params![steam_id, name])?;
+                    player_names.insert(steam_id, name.clone());
+                }
+                Ok(())
+            };
+
+            for level_info in query_results {
+                let stored_level = stored.remove(&level_info.leaderboard_name);
+                if stored_level == Some(serde_json::to_value(level_info)?) {
+                    continue;
+                }
+                delete_entries.execute([&level_info.leaderboard_name])?;
+                delete_level.execute([&level_info.leaderboard_name])?;
+
+                let workshop = level_info.workshop_response.as_ref();
+                insert_level.execute(params![
+                    level_info.leaderboard_name,
+                    level_info.name,
+                    enum_to_sql(level_info.mode),
+                    workshop.map(|x| x.published_file_id),
+                    workshop.map(|x| x.steam_id_owner),
+                    workshop.map(|x| &x.file_name),
+                    workshop.map(|x| &x.title),
+                    workshop.map(|x| f64::from(x.score)),
+                    workshop
+                        .map(|x| serde_json::to_string(&x.tags))
+                        .transpose()?,
+                    workshop.map(|x| &x.preview_url),
+                    level_info.timestamp,
+                    level_info.missing_since,
+                    level_info.leaderboard_response.range_end,
+                ])?;
+
+                if let Some(WorkshopResponse {
+                    steam_id_owner,
+                    author_name: Some(author_name),
+                    ..
+                }) = workshop
+                {
+                    upsert_name(*steam_id_owner, author_name)?;
+                }
+
+                for (position, entry) in level_info.leaderboard_response.entries.iter().enumerate()
+                {
+                    insert_entry.execute(params![
+                        level_info.leaderboard_name,
+                        position,
+                        entry.steam_id,
+                        entry.global_rank,
+                        entry.score,
+                    ])?;
+
+                    if let Some(player_name) = &entry.player_name {
+                        upsert_name(entry.steam_id, player_name)?;
+                    }
+                }
+            }
+
+            for leaderboard_name in stored.keys() {
+                delete_entries.execute([leaderboard_name])?;
+                delete_level.execute([leaderboard_name])?;
+            }
+        }
+
+        set_meta(&tx, QUERY_RESULTS_SAVED_KEY)?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn read_changelist(&self) -> Result<Vec<ChangelistEvent>> {
+        let mut statement = self.connection.prepare(
+            "SELECT kind, leaderboard_name, map_name, mode, map_author, map_preview,
+                    workshop_item_id, steam_id_author, new_steam_id, new_player_name, new_score,
+                    old_steam_id, old_player_name, old_score, fetch_time, rank
+             FROM changelist_events
+             ORDER BY id",
+        )?;
+        let changelist = statement
+            .query_map([], |row| {
+                Ok(ChangelistEvent {
+                    kind: enum_from_sql(row, 0)?,
+                    leaderboard_name: row.get(1)?,
+                    map_name: row.get(2)?,
+                    mode: enum_from_sql(row, 3)?,
+                    map_author: row.get(4)?,
+                    map_preview: row.get(5)?,
+                    workshop_item_id: row.get(6)?,
+                    steam_id_author: row.get(7)?,
+                    new_record: record_from_sql(row, 8)?,
+                    old_record: record_from_sql(row, 11)?,
+                    rank: row.get(15)?,
+                    fetch_time: row.get(14)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(changelist)
+    }
+
+    fn insert_changelist_events(
+        &self,
+        events: &[ChangelistEvent],
+        notifications: &[Notification],
+        replace: bool,
+    ) -> Result<()> {
+        let tx = self.connection.unchecked_transaction()?;
+
+        if replace {
+            tx.execute("DELETE FROM changelist_events", [])?;
+        }
+
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO changelist_events (kind, leaderboard_name, map_name, mode, map_author,
+                                                map_preview, workshop_item_id, steam_id_author,
+                                                new_steam_id, new_player_name, new_score,
+                                                old_steam_id, old_player_name, old_score,
+                                                fetch_time, rank)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+            )?;
+            for event in events {
+                let new_record = event.new_record.as_ref();
+                let old_record = event.old_record.as_ref();
+                insert.execute(params![
+                    enum_to_sql(event.kind),
+                    event.leaderboard_name,
+                    event.map_name,
+                    enum_to_sql(event.mode),
+                    event.map_author,
+                    event.map_preview,
+                    event.workshop_item_id,
+                    event.steam_id_author,
+                    new_record.map(|r| r.steam_id),
+                    new_record.map(|r| &r.player_name),
+                    new_record.map(|r| r.score),
+                    old_record.map(|r| r.steam_id),
+                    old_record.map(|r| &r.player_name),
+                    old_record.map(|r| r.score),
+                    event.fetch_time,
+                    event.rank,
+                ])?;
+            }
+
+            let mut insert = tx.prepare(
+                "INSERT OR IGNORE INTO outbox (id, target, event, created_at)
+                 VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for notification in notifications {
+                insert.execute(params![
+                    notification.id,
+                    notification.target,
+                    serde_json::to_string(&notification.event)?,
+                    notification.created_at,
+                ])?;
+            }
+        }
+
+        set_meta(&tx, CHANGELIST_SAVED_KEY)?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn export_changelist(&self) -> Result<()> {
+        if let Some(path) = &self.changelist_export_path {
+            let legacy_changelist = domain::legacy_changelist(&self.read_changelist()?);
+            file_json_persistence::save_file(&legacy_changelist, path)
+                .context("error exporting the changelist to JSON")?;
+        }
+
+        Ok(())
+    }
+
+    fn read_record_history(&self) -> Result<RecordHistory> {
+        let mut statement = self.connection.prepare(
+            "SELECT leaderboard_name, steam_id, score, first_seen, last_seen
+             FROM record_history
+             ORDER BY leaderboard_name, first_seen",
+        )?;
+        let mut rows = statement.query([])?;
+
+        let mut holdings: BTreeMap<String, Vec<RecordHolding>> = BTreeMap::new();
+        while let Some(row) = rows.next()? {
+            holdings
+                .entry(row.get(0)?)
+                .or_default()
+                .push(RecordHolding {
+                    steam_id: row.get(1)?,
+                    score: row.get(2)?,
+                    first_seen: row.get(3)?,
+                    last_seen: row.get(4)?,
+                });
+        }
+
+        Ok(RecordHistory::from_holdings(holdings))
+    }
+
+    /// Writes the holdings that differ from the stored ones. Normally that's just the latest
+    /// holding of each polled leaderboard, whose `last_seen` moved on.
+    fn write_record_history(&self, record_history: &RecordHistory) -> Result<()> {
+        let stored = self.read_record_history()?;
+
+        let tx = self.connection.unchecked_transaction()?;
+
+        {
+            let mut upsert = tx.prepare(
+                "INSERT INTO record_history (leaderboard_name, steam_id, score, first_seen,
+                                             last_seen)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (leaderboard_name, first_seen) DO UPDATE
+                 SET steam_id = excluded.steam_id,
+                     score = excluded.score,
+                     last_seen = excluded.last_seen",
+            )?;
+            for (leaderboard_name, holdings) in record_history.iter() {
+                let stored_holdings = stored.get(leaderboard_name);
+                for holding in holdings {
+                    if stored_holdings.contains(holding) {
+                        continue;
+                    }
+                    upsert.execute(params![
+                        leaderboard_name,
+                        holding.steam_id,
+                        holding.score,
+                        holding.first_seen,
+                        holding.last_seen,
+                    ])?;
+                }
+            }
+        }
+
+        set_meta(&tx, RECORD_HISTORY_SAVED_KEY)?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn read_name_cache(&self) -> Result<NameCache> {
+        let mut statement = self.connection.prepare(
+            "SELECT steam_id, name, resolved_at
+             FROM player_names
+             WHERE resolved_at IS NOT NULL",
+        )?;
+        let names = statement
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    CachedName {
+                        name: row.get(1)?,
+                        resolved_at: row.get(2)?,
+                    },
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(NameCache::from_names(names))
+    }
+
+    /// Writes the names that differ from the stored ones, i.e. the ones resolved this run.
+    fn write_name_cache(&self, name_cache: &NameCache) -> Result<()> {
+        let stored = self.read_name_cache()?;
+        let stored: HashMap<u64, &CachedName> = stored.iter().collect();
+
+        let tx = self.connection.unchecked_transaction()?;
+
+        {
+            let mut upsert = tx.prepare(
+                "INSERT INTO player_names (steam_id, name, resolved_at) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (steam_id) DO UPDATE
+                 SET name = excluded.name,
+                     resolved_at = excluded.resolved_at",
+            )?;
+            for (steam_id, cached) in name_cache.iter() {
+                if stored.get(&steam_id) == Some(&cached) {
+                    continue;
+                }
+                upsert.execute(params![steam_id, cached.name, cached.resolved_at])?;
+            }
+        }
+
+        set_meta(&tx, NAME_CACHE_SAVED_KEY)?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn read_name_history(&self) -> Result<NameHistory> {
+        let mut statement = self.connection.prepare(
+            "SELECT steam_id, name, first_seen, last_seen
+             FROM name_history
+             ORDER BY steam_id, first_seen",
+        )?;
+        let mut rows = statement.query([])?;
+
+        let mut sightings: BTreeMap<u64, Vec<NameSighting>> = BTreeMap::new();
+        while let Some(row) = rows.next()? {
+            sightings
+                .entry(row.get(0)?)
+                .or_default()
+                .push(NameSighting {
+                    name: row.get(1)?,
+                    first_seen: row.get(2)?,
+                    last_seen: row.get(3)?,
+                });
+        }
+
+        Ok(NameHistory::from_sightings(sightings))
+    }
+
+    /// Writes the sightings that differ from the stored ones. Normally that's just the latest
+    /// sighting of each resolved name, whose `last_seen` moved on, and the new names.
+    fn write_name_history(&self, name_history: &NameHistory) -> Result<()> {
+        let stored = self.read_name_history()?;
+
+        let tx = self.connection.unchecked_transaction()?;
+
+        {
+            let mut upsert = tx.prepare(
+                "INSERT INTO name_history (steam_id, name, first_seen, last_seen)
+                 VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (steam_id, first_seen) DO UPDATE
+                 SET name = excluded.name,
+                     last_seen = excluded.last_seen",
+            )?;
+            for (steam_id, sightings) in name_history.iter() {
+                let stored_sightings = stored.get(steam_id);
+                for sighting in sightings {
+                    if stored_sightings.contains(sighting) {
+                        continue;
+                    }
+                    upsert.execute(params![
+                        steam_id,
+                        sighting.name,
+                        sighting.first_seen,
+                        sighting.last_seen,
+                    ])?;
+                }
+            }
+        }
+
+        set_meta(&tx, NAME_HISTORY_SAVED_KEY)?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn get_meta(&self, key: &str) -> Result<Option<String>> {
+        let value = self
+            .connection
+            .query_row("SELECT value FROM meta WHERE key = ?1", [key], |row| {
+                row.get(0)
+            })
+            .optional()?;
+
+        Ok(value)
+    }
+
+    fn load_player_names(&self) -> Result<HashMap<u64, String>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT steam_id, name FROM player_names")?;
+        let names = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(names)
+    }
+
+    fn load_leaderboard_entries(
+        &self,
+        player_names: &HashMap<u64, String>,
+    ) -> Result<HashMap<String, Vec<LeaderboardEntry>>> {
+        let mut statement = self.connection.prepare(
+            "SELECT leaderboard_name, steam_id, global_rank, score
+             FROM leaderboard_entries
+             ORDER BY leaderboard_name, position",
+        )?;
+        let mut rows = statement.query([])?;
+
+        let mut entries: HashMap<String, Vec<LeaderboardEntry>> = HashMap::new();
+        while let Some(row) = rows.next()? {
+            let steam_id: u64 = row.get(1)?;
+            entries
+                .entry(row.get(0)?)
+                .or_default()
+                .push(LeaderboardEntry {
+                    steam_id,
+                    global_rank: row.get(2)?,
+                    score: row.get(3)?,
+                    player_name: player_names.get(&steam_id).cloned(),
+                });
+        }
+
+        Ok(entries)
+    }
+}
+
+impl Persistence for SqlitePersistence {
+    fn load_query_results(&self) -> Result<Vec<LevelInfo>, LoadError> {
+        if self.get_meta(QUERY_RESULTS_SAVED_KEY)?.is_none() {
+            return Err(LoadError::DoesNotExist);
+        }
+
+        Ok(self.read_query_results()?)
+    }
+
+    fn save_query_results(&self, query_results: &[LevelInfo]) -> Result<()> {
+        self.write_query_results(query_results)
+    }
+
+    fn load_changelist(&self) -> Result<Vec<ChangelistEvent>, LoadError> {
+        if self.get_meta(CHANGELIST_SAVED_KEY)?.is_none() {
+            if self
+                .changelist_export_path
+                .as_ref()
+                .is_some_and(|path| path.exists())
+            {
+                // Starting a new changelist would overwrite the legacy one in the export
+                return Err(LoadError::NeedsMigration);
+            }
+            return Err(LoadError::DoesNotExist);
+        }
+
+        Ok(self.read_changelist()?)
+    }
+
+    fn append_changelist(
+        &self,
+        events: &[ChangelistEvent],
+        notifications: &[Notification],
+    ) -> Result<()> {
+        self.insert_changelist_events(events, notifications, false)?;
+        self.export_changelist()
+    }
+
+    fn replace_changelist(&self, events: &[ChangelistEvent]) -> Result<()> {
+        self.insert_changelist_events(events, &[], true)?;
+        self.export_changelist()
+    }
+
+    fn load_outbox(&self) -> Result<Vec<Notification>> {
+        let mut statement = self.connection.prepare(
+            "SELECT id, target, event, created_at
+             FROM outbox
+             ORDER BY position",
+        )?;
+        let outbox = statement
+            .query_map([], |row| {
+                let event: String = row.get(2)?;
+                Ok(Notification {
+                    id: row.get(0)?,
+                    target: row.get(1)?,
+                    event: serde_json::from_str(&event).map_err(|e| {
+                        rusqlite::Error::FromSqlConversionFailure(
+                            2,
+                            rusqlite::types::Type::Text,
+                            e.into(),
+                        )
+                    })?,
+                    created_at: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(outbox)
+    }
+
+    fn remove_from_outbox(&self, ids: &[String]) -> Result<()> {
+        let tx = self.connection.unchecked_transaction()?;
+        {
+            let mut delete = tx.prepare("DELETE FROM outbox WHERE id = ?1")?;
+            for id in ids {
+                delete.execute([id])?;
+            }
+        }
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn load_record_history(&self) -> Result<RecordHistory, LoadError> {
+        if self.get_meta(RECORD_HISTORY_SAVED_KEY)?.is_none() {
+            return Err(LoadError::DoesNotExist);
+        }
+
+        Ok(self.read_record_history()?)
+    }
+
+    fn save_record_history(&self, record_history: &RecordHistory) -> Result<()> {
+        self.write_record_history(record_history)
+    }
+
+    fn load_fetch_state(&self) -> Result<FetchState, LoadError> {
+        let value = self
+            .get_meta(FETCH_STATE_KEY)?
+            .ok_or(LoadError::DoesNotExist)?;
+
+        serde_json::from_str(&value).map_err(|e| LoadError::Other(e.into()))
+    }
+
+    fn save_fetch_state(&self, fetch_state: &FetchState) -> Result<()> {
+        self.connection.execute(
+            "INSERT INTO meta (key, value) VALUES (?1, ?2)
+             ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+            (FETCH_STATE_KEY, serde_json::to_string(fetch_state)?),
+        )?;
+
+        Ok(())
+    }
+
+    fn load_name_cache(&self) -> Result<NameCache, LoadError> {
+        if self.get_meta(NAME_CACHE_SAVED_KEY)?.is_none() {
+            return Err(LoadError::DoesNotExist);
+        }
+
+        Ok(self.read_name_cache()?)
+    }
+
+    fn save_name_cache(&self, name_cache: &NameCache) -> Result<()> {
+        self.write_name_cache(name_cache)
+    }
+
+    fn load_name_history(&self) -> Result<NameHistory, LoadError> {
+        if self.get_meta(NAME_HISTORY_SAVED_KEY)?.is_none() {
+            return Err(LoadError::DoesNotExist);
+        }
+
+        Ok(self.read_name_history()?)
+    }
+
+    fn save_name_history(&self, name_history: &NameHistory) -> Result<()> {
+        self.write_name_history(name_history)
+    }
+
+    /// `PRAGMA data_version` only changes when other connections commit, so this connection's own
+    /// changes are counted too.
+    fn data_version(&self) -> Result<u64> {
+        let data_version: i64 =
+            self.connection
+                .pragma_query_value(None, "data_version", |row| row.get(0))?;
+        let total_changes: i64 =
+            self.connection
+                .query_row("SELECT total_changes()", [], |row| row.get(0))?;
+
+        Ok(((data_version as u64) << 32) ^ total_changes as u64)
+    }
+}
+
+fn set_meta(connection: &Connection, key: &str) -> Result<()> {
+    connection.execute(
+        "INSERT INTO meta (key, value) VALUES (?1, datetime('now'))
+         ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+        [key],
+    )?;
+
+    Ok(())
+}
+
+fn migrate(connection: &Connection) -> Result<()> {
+    let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = connection.unchecked_transaction()?;
+        tx.execute_batch(migration)
+            .with_context(|| format!("error migrating the database to version {}", i + 1))?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+/// Stores a fieldless enum as the string serde would use for it.
+fn enum_to_sql<T: Serialize>(value: T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(s)) => s,
+        _ => unreachable!("fieldless enums serialize to strings"),
+    }
+}
+
+fn enum_from_sql<T: DeserializeOwned>(row: &rusqlite::Row, column: usize) -> rusqlite::Result<T> {
+    let value: String = row.get(column)?;
+    serde_json::from_value(serde_json::Value::String(value)).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(column, rusqlite::types::Type::Text, e.into())
+    })
+}
+
+/// Reads a record stored as steam id, player name and score in three consecutive columns.
+fn record_from_sql(
+    row: &rusqlite::Row,
+    column: usize,
+) -> rusqlite::Result<Option<ChangelistRecord>> {
+    let steam_id: Option<u64> = row.get(column)?;
+
+    steam_id
+        .map(|steam_id| {
+            Ok(ChangelistRecord {
+                steam_id,
+                player_name: row.get(column + 1)?,
+                score: row.get(column + 2)?,
+            })
+        })
+        .transpose()
+}
+
+#[test]
+fn test_round_trip() {
+    use crate::domain::ChangelistEventKind;
+    use chrono::Utc;
+    use distance_util::LeaderboardGameMode;
+
+    let dir = tempfile::tempdir().unwrap();
+    let persistence = SqlitePersistence::open(dir.path().join("db.sqlite3"))
+        .unwrap()
+        .with_changelist_export(dir.path().join("changelist.json"));
+
+    assert!(matches!(
+        persistence.load_query_results(),
+        Err(LoadError::DoesNotExist)
+    ));
+    assert!(matches!(
+        persistence.load_changelist(),
+        Err(LoadError::DoesNotExist)
+    ));
+
+    let query_results = vec![LevelInfo {
+        name: "Test Level".into(),
+        mode: LeaderboardGameMode::Stunt,
+        leaderboard_name: "test_level_3_1_stable".into(),
+        workshop_response: Some(WorkshopResponse {
+            published_file_id: 100,
+            steam_id_owner: 1,
+            file_name: "test_level.bytes".into(),
+            title: "Test Level".into(),
+            score: 0.5,
+            tags: Box::new(["Stunt".into()]),
+            author_name: Some("Author".into()),
+            preview_url: "https://example.com/preview.png".into(),
+        }),
+        leaderboard_response: LeaderboardResponse {
+            entries: Box::new([LeaderboardEntry {
+                steam_id: 76561197960287930,
+                global_rank: 1,
+                score: 123_456,
+                player_name: Some("Player".into()),
+            }]),
+            range_end: 10,
+        },
+        timestamp: Utc::now(),
+        missing_since: Some(Utc::now()),
+    }];
+    persistence.save_query_results(&query_results).unwrap();
+    assert_eq!(
+        serde_json::to_value(persistence.load_query_results().unwrap()).unwrap(),
+        serde_json::to_value(&query_results).unwrap()
+    );
+
+    // Only the changed level is rewritten, and the one that's gone is deleted
+    let mut changed = query_results.clone();
+    changed.push(LevelInfo {
+        name: "Other Level".into(),
+        leaderboard_name: "other_level".into(),
+        workshop_response: None,
+        missing_since: None,
+        ..query_results[0].clone()
+    });
+    persistence.save_query_results(&changed).unwrap();
+    changed[1].leaderboard_response.entries[0].score = 120_000;
+    changed.remove(0);
+    persistence.save_query_results(&changed).unwrap();
+    assert_eq!(
+        serde_json::to_value(persistence.load_query_results().unwrap()).unwrap(),
+        serde_json::to_value(&changed).unwrap()
+    );
+    persistence.save_query_results(&query_results).unwrap();
+
+    let fetch_time = Utc::now();
+    let event = |score: i32| ChangelistEvent {
+        kind: ChangelistEventKind::RecordRemoved,
+        leaderboard_name: Some("test_level_3_1_stable".into()),
+        map_name: "Test Level".into(),
+        mode: LeaderboardGameMode::Stunt,
+        map_author: Some("Author".into()),
+        map_preview: None,
+        workshop_item_id: Some(100),
+        steam_id_author: Some(1),
+        new_record: Some(ChangelistRecord {
+            steam_id: 76561197960287930,
+            player_name: "Player".into(),
+            score,
+        }),
+        old_record: None,
+        rank: None,
+        fetch_time,
+    };
+    persistence.append_changelist(&[event(1)], &[]).unwrap();
+    persistence.append_changelist(&[event(2)], &[]).unwrap();
+    let changelist = persistence.load_changelist().unwrap();
+    assert_eq!(
+        serde_json::to_value(&changelist).unwrap(),
+        serde_json::to_value([event(1), event(2)]).unwrap()
+    );
+    let notification = Notification {
+        id: "test/1".into(),
+        target: "test".into(),
+        event: event(3),
+        created_at: fetch_time,
+    };
+    persistence
+        .append_changelist(&[], &[notification.clone(), notification])
+        .unwrap();
+    let outbox = persistence.load_outbox().unwrap();
+    assert_eq!(outbox.len(), 1);
+    assert_eq!(
+        serde_json::to_value(&outbox[0].event).unwrap(),
+        serde_json::to_value(event(3)).unwrap()
+    );
+    persistence.remove_from_outbox(&["test/1".into()]).unwrap();
+    assert!(persistence.load_outbox().unwrap().is_empty());
+
+    let exported: serde_json::Value =
+        serde_json::from_reader(std::fs::File::open(dir.path().join("changelist.json")).unwrap())
+            .unwrap();
+    assert_eq!(
+        exported,
+        serde_json::to_value(domain::legacy_changelist(&changelist)).unwrap()
+    );
+
+    let mut record_history = RecordHistory::default();
+    record_history.observe(&query_results);
+    persistence.save_record_history(&record_history).unwrap();
+    record_history.observe(&query_results);
+    persistence.save_record_history(&record_history).unwrap();
+    assert_eq!(
+        serde_json::to_value(persistence.load_record_history().unwrap()).unwrap(),
+        serde_json::to_value(&record_history).unwrap()
+    );
+
+    assert!(matches!(
+        persistence.load_fetch_state(),
+        Err(LoadError::DoesNotExist)
+    ));
+    let fetch_state = FetchState {
+        last_full_sweep: Some(Utc::now()),
+        workshop_listed_at: Some(Utc::now()),
+        ..FetchState::default()
+    };
+    persistence.save_fetch_state(&fetch_state).unwrap();
+    assert_eq!(persistence.load_fetch_state().unwrap(), fetch_state);
+
+    assert!(matches!(
+        persistence.load_name_cache(),
+        Err(LoadError::DoesNotExist)
+    ));
+    let mut name_cache = NameCache::default();
+    name_cache.update([(1, Some("Author".into()))], fetch_time);
+    persistence.save_name_cache(&name_cache).unwrap();
+    assert_eq!(
+        serde_json::to_value(persistence.load_name_cache().unwrap()).unwrap(),
+        serde_json::to_value(&name_cache).unwrap()
+    );
+
+    let mut name_history = NameHistory::default();
+    name_history.observe(1, "Author", fetch_time);
+    persistence.save_name_history(&name_history).unwrap();
+    name_history.observe(1, "Renamed", fetch_time + chrono::Duration::hours(1));
+    persistence.save_name_history(&name_history).unwrap();
+    assert_eq!(
+        persistence.load_name_history().unwrap().get(1),
+        name_history.get(1)
+    );
+}
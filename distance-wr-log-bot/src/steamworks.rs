@@ -1,4 +1,6 @@
+use crate::data_source::DataSource;
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use futures::{pin_mut, Stream, TryStreamExt};
 use itertools::Itertools;
 use serde_derive::{Deserialize, Serialize};
@@ -6,6 +8,19 @@ use serde_derive::{Deserialize, Serialize};
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LeaderboardResponse {
     pub entries: Box<[LeaderboardEntry]>,
+    /// The last rank that was requested. Players ranked below it aren't in `entries` even if they
+    /// have a score.
+    #[serde(default = "LeaderboardResponse::legacy_range_end")]
+    pub range_end: i32,
+}
+
+impl LeaderboardResponse {
+    /// Responses saved before the range was configurable held the top 2.
+    pub const LEGACY_RANGE_END: i32 = 2;
+
+    fn legacy_range_end() -> i32 {
+        Self::LEGACY_RANGE_END
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -16,7 +31,7 @@ pub struct LeaderboardEntry {
     pub player_name: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct WorkshopResponse {
     pub published_file_id: u64,
     pub steam_id_owner: u64,
@@ -33,21 +48,26 @@ pub struct Steamworks {
     grpc_client: distance_steam_data_client::Client,
     web_client: reqwest::Client,
     web_api_key: String,
+    app_id: u32,
 }
 
 impl Steamworks {
     pub async fn new(
         grpc_address: impl Into<String>,
         web_api_key: impl Into<String>,
+        app_id: u32,
     ) -> Result<Self> {
         Ok(Steamworks {
             grpc_client: distance_steam_data_client::Client::connect(grpc_address.into()).await?,
             web_client: reqwest::Client::new(),
             web_api_key: web_api_key.into(),
+            app_id,
         })
     }
+}
 
-    pub async fn get_leaderboard_range(
+impl DataSource for Steamworks {
+    async fn get_leaderboard_range(
         &self,
         leaderboard_name: &str,
         start: i32,
@@ -68,26 +88,28 @@ impl Steamworks {
             .collect_vec()
             .into_boxed_slice();
 
-        Ok(LeaderboardResponse { entries })
+        Ok(LeaderboardResponse {
+            entries,
+            range_end: end,
+        })
     }
 
-    pub fn get_all_workshop_sprint_challenge_stunt_levels(
+    fn get_all_workshop_sprint_challenge_stunt_levels(
         &self,
     ) -> impl Stream<Item = Result<WorkshopResponse>> + '_ {
         ez_stream::try_unbounded(move |tx| async move {
             let stream = steam_workshop::query_all_files(
                 self.web_client.clone(),
                 self.web_api_key.clone(),
-                233610,
+                self.app_id,
             );
             pin_mut!(stream);
             while let Some(chunk) = stream.try_next().await? {
                 for details in chunk {
-                    let is_relevant_level = details
-                        .tags
-                        .iter()
-                        .any(|tag| ["Sprint", "Challenge", "Stunt"].contains(&tag.tag.as_str()));
-                    if !is_relevant_level || details.filename.is_empty() {
+                    if !is_relevant_level(
+                        details.tags.iter().map(|tag| &tag.tag),
+                        &details.filename,
+                    ) {
                         continue;
                     }
 
@@ -108,10 +130,126 @@ impl Steamworks {
         })
     }
 
-    pub async fn resolve_steam_names(
+    fn get_workshop_levels_updated_since(
+        &self,
+        since: DateTime<Utc>,
+    ) -> impl Stream<Item = Result<WorkshopResponse>> + '_ {
+        ez_stream::try_unbounded(move |tx| async move {
+            let app_id = self.app_id.to_string();
+            let query_type = RANKED_BY_LAST_UPDATED_DATE.to_string();
+            let page_size = QUERY_FILES_PAGE_SIZE.to_string();
+            let mut cursor = "*".to_owned();
+            loop {
+                let response: QueryFilesResponse = self
+                    .web_client
+                    .get(QUERY_FILES_URL)
+                    .query(&[
+                        ("key", self.web_api_key.as_str()),
+                        ("appid", &app_id),
+                        ("query_type", &query_type),
+                        ("cursor", &cursor),
+                        ("numperpage", &page_size),
+                        ("return_tags", "true"),
+                        ("return_vote_data", "true"),
+                    ])
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                let response = response.response;
+
+                for details in &response.publishedfiledetails {
+                    // Sorted by update time, newest first
+                    if details.time_updated < since.timestamp() {
+                        return Ok(());
+                    }
+                    if !is_relevant_level(
+                        details.tags.iter().map(|tag| &tag.tag),
+                        &details.filename,
+                    ) {
+                        continue;
+                    }
+
+                    tx.send(WorkshopResponse {
+                        published_file_id: details.publishedfileid.parse()?,
+                        steam_id_owner: details.creator.parse()?,
+                        file_name: details.filename.clone(),
+                        title: details.title.clone(),
+                        score: details.vote_data.as_ref().map_or(0.0, |x| x.score),
+                        tags: details.tags.iter().map(|tag| tag.tag.clone()).collect(),
+                        author_name: None,
+                        preview_url: details.preview_url.clone(),
+                    })?;
+                }
+
+                match response.next_cursor {
+                    Some(next) if !response.publishedfiledetails.is_empty() && next != cursor => {
+                        cursor = next;
+                    }
+                    _ => return Ok(()),
+                }
+            }
+        })
+    }
+
+    async fn resolve_steam_names(
         &self,
         steam_ids: Vec<u64>,
     ) -> Result<impl Iterator<Item = Option<String>>> {
         self.grpc_client.persona_names(steam_ids).await
     }
 }
+
+/// Whether a workshop item is a level with a Sprint, Challenge, or Stunt leaderboard.
+fn is_relevant_level<'a>(mut tags: impl Iterator<Item = &'a String>, file_name: &str) -> bool {
+    tags.any(|tag| ["Sprint", "Challenge", "Stunt"].contains(&tag.as_str()))
+        && !file_name.is_empty()
+}
+
+/// The Steam Web API method `steam_workshop::query_all_files` uses, which can also list items by
+/// update time.
+const QUERY_FILES_URL: &str = "https://api.steampowered.com/IPublishedFileService/QueryFiles/v1/";
+
+/// `k_PublishedFileQueryType_RankedByLastUpdatedDate`
+const RANKED_BY_LAST_UPDATED_DATE: u32 = 21;
+
+const QUERY_FILES_PAGE_SIZE: u32 = 100;
+
+#[derive(Debug, Deserialize)]
+struct QueryFilesResponse {
+    response: QueryFilesResults,
+}
+
+#[derive(Debug, Deserialize)]
+struct QueryFilesResults {
+    #[serde(default)]
+    publishedfiledetails: Vec<PublishedFileDetails>,
+    next_cursor: Option<String>,
+}
+
+/// The fields of a workshop item the bot uses. Steam sends 64-bit ids as strings.
+#[derive(Debug, Deserialize)]
+struct PublishedFileDetails {
+    publishedfileid: String,
+    creator: String,
+    #[serde(default)]
+    filename: String,
+    title: String,
+    time_updated: i64,
+    #[serde(default)]
+    tags: Vec<PublishedFileTag>,
+    vote_data: Option<PublishedFileVoteData>,
+    #[serde(default)]
+    preview_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PublishedFileTag {
+    tag: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PublishedFileVoteData {
+    score: f32,
+}
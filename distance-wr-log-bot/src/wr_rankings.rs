@@ -0,0 +1,288 @@
+use crate::domain::LevelInfo;
+use crate::file_json_persistence;
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use distance_util::LeaderboardGameMode;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::Path;
+
+const MODES: [Option<LeaderboardGameMode>; 4] = [
+    None,
+    Some(LeaderboardGameMode::Sprint),
+    Some(LeaderboardGameMode::Challenge),
+    Some(LeaderboardGameMode::Stunt),
+];
+
+/// Which levels a ranking counts.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    Official,
+    Workshop,
+    All,
+}
+
+impl Scope {
+    fn contains(self, level: &LevelInfo) -> bool {
+        match self {
+            Scope::Official => level.workshop_response.is_none(),
+            Scope::Workshop => level.workshop_response.is_some(),
+            Scope::All => true,
+        }
+    }
+}
+
+/// Every ranking computed from one snapshot, as written to `rankings.json` and to the daily files
+/// in `rankings/`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rankings {
+    pub generated_at: DateTime<Utc>,
+    /// The day of the rankings `previous_rank` refers to.
+    pub compared_to: Option<NaiveDate>,
+    pub rankings: Vec<Ranking>,
+}
+
+/// Players ranked by how many records they currently hold in a scope and mode, or in every mode
+/// if `mode` is `None`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Ranking {
+    pub scope: Scope,
+    pub mode: Option<LeaderboardGameMode>,
+    pub players: Vec<RankedPlayer>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RankedPlayer {
+    /// Players with the same number of records share a rank.
+    pub rank: usize,
+    pub steam_id: u64,
+    pub player_name: Option<String>,
+    pub records: usize,
+    /// The player's rank in the compared rankings, if they were ranked there.
+    pub previous_rank: Option<usize>,
+}
+
+/// Ranks the holders of the current records in every scope and mode.
+///
+/// Players with the same number of records share a rank, and are listed by their total number of
+/// records across all levels, then by steam id.
+pub fn compute_rankings(
+    level_infos: &[LevelInfo],
+    previous: Option<(NaiveDate, &Rankings)>,
+    now: DateTime<Utc>,
+) -> Rankings {
+    let holders: Vec<_> = level_infos
+        .iter()
+        .filter_map(|level| Some((level, level.leaderboard_response.entries.first()?)))
+        .collect();
+    let total_records =
+        holders
+            .iter()
+            .fold(HashMap::<u64, usize>::new(), |mut counts, (_, top)| {
+                *counts.entry(top.steam_id).or_default() += 1;
+                counts
+            });
+
+    let mut rankings = Vec::new();
+    for scope in [Scope::All, Scope::Official, Scope::Workshop] {
+        for mode in MODES {
+            let mut counts: HashMap<u64, (usize, Option<&str>)> = HashMap::new();
+            for (level, top) in &holders {
+                if scope.contains(level) && mode.is_none_or(|mode| level.mode == mode) {
+                    let (records, name) = counts.entry(top.steam_id).or_default();
+                    *records += 1;
+                    *name = name.or(top.player_name.as_deref());
+                }
+            }
+
+            let mut players: Vec<_> = counts.into_iter().collect();
+            players.sort_by_key(|&(steam_id, (records, _))| {
+                (
+                    std::cmp::Reverse(records),
+                    std::cmp::Reverse(total_records[&steam_id]),
+                    steam_id,
+                )
+            });
+
+            let previous_ranks: HashMap<u64, usize> = previous
+                .and_then(|(_, previous)| {
+                    previous
+                        .rankings
+                        .iter()
+                        .find(|x| x.scope == scope && x.mode == mode)
+                })
+                .map(|ranking| {
+                    ranking
+                        .players
+                        .iter()
+                        .map(|player| (player.steam_id, player.rank))
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            let mut ranked: Vec<RankedPlayer> = Vec::with_capacity(players.len());
+            for (i, (steam_id, (records, player_name))) in players.into_iter().enumerate() {
+                let rank = match ranked.last() {
+                    Some(last) if last.records == records => last.rank,
+                    _ => i + 1,
+                };
+                ranked.push(RankedPlayer {
+                    rank,
+                    steam_id,
+                    player_name: player_name.map(str::to_owned),
+                    records,
+                    previous_rank: previous_ranks.get(&steam_id).copied(),
+                });
+            }
+
+            rankings.push(Ranking {
+                scope,
+                mode,
+                players: ranked,
+            });
+        }
+    }
+
+    Rankings {
+        generated_at: now,
+        compared_to: previous.map(|(day, _)| day),
+        rankings,
+    }
+}
+
+/// Writes the current rankings to `rankings.json` in `dir`, compared to the latest daily rankings
+/// from before today, and saves them as today's daily rankings in `rankings/<date>.json`.
+pub fn write_rankings(dir: &Path, level_infos: &[LevelInfo], now: DateTime<Utc>) -> Result<()> {
+    let daily_dir = dir.join("rankings");
+    fs::create_dir_all(&daily_dir)?;
+
+    let today = now.date_naive();
+    let previous_day = daily_rankings_days(&daily_dir)?
+        .into_iter()
+        .filter(|&day| day < today)
+        .max();
+    let previous = match previous_day {
+        Some(day) => {
+            let path = daily_dir.join(format!("{day}.json"));
+            let file =
+                File::open(&path).with_context(|| format!("couldn't open {}", path.display()))?;
+            let rankings: Rankings = serde_json::from_reader(BufReader::new(file))
+                .with_context(|| format!("couldn't parse {}", path.display()))?;
+            Some((day, rankings))
+        }
+        None => None,
+    };
+
+    let rankings = compute_rankings(
+        level_infos,
+        previous.as_ref().map(|(day, rankings)| (*day, rankings)),
+        now,
+    );
+    let data = serde_json::to_vec(&rankings)?;
+    file_json_persistence::write_atomically(&data, &dir.join("rankings.json"))?;
+    file_json_persistence::write_atomically(&data, &daily_dir.join(format!("{today}.json")))
+}
+
+/// The days that have daily rankings.
+fn daily_rankings_days(daily_dir: &Path) -> Result<Vec<NaiveDate>> {
+    let entries = match fs::read_dir(daily_dir) {
+        Ok(x) => x,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut days = Vec::new();
+    for entry in entries {
+        let file_name = entry?.file_name();
+        let day: Option<NaiveDate> = file_name
+            .to_str()
+            .and_then(|x| x.strip_suffix(".json"))
+            .and_then(|x| x.parse().ok());
+        days.extend(day);
+    }
+
+    Ok(days)
+}
+
+#[test]
+fn test_compute_rankings() {
+    use crate::steamworks::{LeaderboardEntry, LeaderboardResponse, WorkshopResponse};
+
+    let now = Utc::now();
+    let level = |mode, workshop: bool, steam_id| LevelInfo {
+        name: String::new(),
+        mode,
+        leaderboard_name: String::new(),
+        workshop_response: workshop.then(|| WorkshopResponse {
+            published_file_id: 1,
+            steam_id_owner: 1,
+            file_name: String::new(),
+            title: String::new(),
+            score: 0.5,
+            tags: Box::new([]),
+            author_name: None,
+            preview_url: String::new(),
+        }),
+        leaderboard_response: LeaderboardResponse {
+            entries: Box::new([LeaderboardEntry {
+                steam_id,
+                global_rank: 1,
+                score: 0,
+                player_name: Some(format!("Player {steam_id}")),
+            }]),
+            range_end: 2,
+        },
+        timestamp: now,
+        missing_since: None,
+    };
+    let sprint = LeaderboardGameMode::Sprint;
+    let stunt = LeaderboardGameMode::Stunt;
+    let level_infos = [
+        level(sprint, false, 1),
+        level(sprint, true, 2),
+        level(sprint, true, 3),
+        level(stunt, true, 3),
+        level(stunt, false, 4),
+    ];
+    let ranking = |rankings: &Rankings, scope, mode| -> Vec<_> {
+        rankings
+            .rankings
+            .iter()
+            .find(|x| x.scope == scope && x.mode == mode)
+            .unwrap()
+            .players
+            .iter()
+            .map(|x| (x.rank, x.steam_id, x.records, x.previous_rank))
+            .collect()
+    };
+
+    let yesterday = compute_rankings(&level_infos[..3], None, now);
+    assert_eq!(yesterday.rankings.len(), 12);
+    assert_eq!(
+        ranking(&yesterday, Scope::All, None),
+        [(1, 1, 1, None), (1, 2, 1, None), (1, 3, 1, None)]
+    );
+
+    // Ties share a rank, and are broken by the total number of records
+    let today = compute_rankings(&level_infos, Some((now.date_naive(), &yesterday)), now);
+    assert_eq!(
+        ranking(&today, Scope::All, None),
+        [
+            (1, 3, 2, Some(1)),
+            (2, 1, 1, Some(1)),
+            (2, 2, 1, Some(1)),
+            (2, 4, 1, None)
+        ]
+    );
+    assert_eq!(
+        ranking(&today, Scope::Workshop, Some(sprint)),
+        [(1, 3, 1, Some(1)), (1, 2, 1, Some(1))]
+    );
+    assert_eq!(
+        ranking(&today, Scope::Official, Some(stunt)),
+        [(1, 4, 1, None)]
+    );
+}
@@ -0,0 +1,723 @@
+//! Configuration shared by the bot and the manager.
+//!
+//! Everything is read from a TOML file, with environment variables overriding individual keys.
+//! Every key has a default, so the file is optional.
+
+#![warn(
+    deprecated_in_future,
+    macro_use_extern_crate,
+    missing_debug_implementations,
+    unused_qualifications
+)]
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::{env, fs, io};
+use toml::Value;
+
+/// The environment variable holding the path of the configuration file.
+pub const CONFIG_PATH_VAR: &str = "WR_LOG_CONFIG";
+
+/// Where the configuration file is looked for if [`CONFIG_PATH_VAR`] isn't set. Unlike an
+/// explicitly given file, this one doesn't have to exist.
+const DEFAULT_CONFIG_PATH: &str = "/data/config.toml";
+
+/// The environment variables that override configuration keys.
+const ENV_OVERRIDES: &[(&str, &str, EnvKind)] = &[
+    ("WR_LOG_DATA_DIR", "paths.data_dir", EnvKind::String),
+    ("SQLITE_PATH", "paths.sqlite", EnvKind::String),
+    ("WR_LOG_SNAPSHOTS_DIR", "paths.snapshots", EnvKind::String),
+    (
+        "GRPC_SERVER_ADDRESS",
+        "steam.grpc_server_address",
+        EnvKind::String,
+    ),
+    ("STEAM_WEB_API_KEY", "steam.web_api_key", EnvKind::String),
+    ("WR_LOG_STEAM_APP_ID", "steam.app_id", EnvKind::Integer),
+    ("FIXTURE_PATH", "steam.fixture_path", EnvKind::String),
+    (
+        "WR_LOG_FETCH_MAX_CONCURRENT_REQUESTS",
+        "fetch.max_concurrent_requests",
+        EnvKind::Integer,
+    ),
+    ("WR_LOG_FETCH_TIMEOUT", "fetch.timeout", EnvKind::String),
+    (
+        "WR_LOG_FETCH_TIME_BUDGET",
+        "fetch.time_budget",
+        EnvKind::String,
+    ),
+    (
+        "WR_LOG_FETCH_OFFICIAL_LEADERBOARD_ENTRIES",
+        "fetch.official_leaderboard_entries",
+        EnvKind::Integer,
+    ),
+    (
+        "WR_LOG_FETCH_WORKSHOP_LEADERBOARD_ENTRIES",
+        "fetch.workshop_leaderboard_entries",
+        EnvKind::Integer,
+    ),
+    (
+        "WR_LOG_FETCH_NAME_RESOLUTION_CHUNK_SIZE",
+        "fetch.name_resolution_chunk_size",
+        EnvKind::Integer,
+    ),
+    ("WR_LOG_FETCH_NAME_TTL", "fetch.name_ttl", EnvKind::String),
+    (
+        "WR_LOG_LEVEL_REMOVAL_GRACE_PERIOD",
+        "fetch.level_removal_grace_period",
+        EnvKind::String,
+    ),
+    (
+        "WR_LOG_FETCH_INCREMENTAL",
+        "fetch.incremental",
+        EnvKind::Boolean,
+    ),
+    (
+        "WR_LOG_FETCH_FULL_SWEEP_INTERVAL",
+        "fetch.full_sweep_interval",
+        EnvKind::String,
+    ),
+    (
+        "WR_LOG_FETCH_HOT_PERIOD",
+        "fetch.hot_period",
+        EnvKind::String,
+    ),
+    (
+        "WR_LOG_FETCH_COLD_POLL_INTERVAL",
+        "fetch.cold_poll_interval",
+        EnvKind::String,
+    ),
+    (
+        "PERSISTENCE_BACKEND",
+        "persistence.backend",
+        EnvKind::String,
+    ),
+    ("WR_LOG_STATS_ENABLED", "stats.enabled", EnvKind::Boolean),
+    (
+        "WR_LOG_RANK_EVENTS_ENABLED",
+        "rank_events.enabled",
+        EnvKind::Boolean,
+    ),
+    (
+        "WR_LOG_RANK_EVENTS_TOP",
+        "rank_events.top",
+        EnvKind::Integer,
+    ),
+    (
+        "WR_LOG_SNAPSHOTS_ENABLED",
+        "snapshots.enabled",
+        EnvKind::Boolean,
+    ),
+    (
+        "WR_LOG_SNAPSHOTS_KEEP_ALL",
+        "snapshots.keep_all",
+        EnvKind::String,
+    ),
+    (
+        "WR_LOG_SNAPSHOTS_KEEP_HOURLY",
+        "snapshots.keep_hourly",
+        EnvKind::String,
+    ),
+    (
+        "WR_LOG_SNAPSHOTS_KEEP_DAILY",
+        "snapshots.keep_daily",
+        EnvKind::String,
+    ),
+    ("FEED_FORMATS", "feeds.formats", EnvKind::List),
+    ("FEED_LINK", "feeds.link", EnvKind::String),
+    (
+        "DISCORD_WEBHOOKS_PATH",
+        "discord.webhooks_path",
+        EnvKind::String,
+    ),
+    ("HTTP_API_ADDRESS", "http_api.address", EnvKind::String),
+    (
+        "WR_LOG_UPDATE_PERIOD",
+        "manager.update_period",
+        EnvKind::String,
+    ),
+    (
+        "WR_LOG_MAX_UPDATE_DURATION",
+        "manager.max_update_duration",
+        EnvKind::String,
+    ),
+    ("WR_LOG_BOT_PATH", "manager.bot_path", EnvKind::String),
+    (
+        "HEALTHCHECKS_URL",
+        "manager.healthchecks_url",
+        EnvKind::String,
+    ),
+];
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub paths: Paths,
+    pub steam: Steam,
+    pub fetch: Fetch,
+    pub persistence: Persistence,
+    pub snapshots: Snapshots,
+    pub stats: Stats,
+    pub rank_events: RankEvents,
+    pub feeds: Feeds,
+    pub discord: Discord,
+    pub http_api: HttpApi,
+    pub manager: Manager,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Paths {
+    /// Where the stored data, the legacy changelist export and the feeds go.
+    pub data_dir: PathBuf,
+    /// The SQLite database. Defaults to `wr-log.sqlite3` in the data directory.
+    pub sqlite: Option<PathBuf>,
+    /// The snapshot archive. Defaults to `snapshots` in the data directory.
+    pub snapshots: Option<PathBuf>,
+}
+
+impl Paths {
+    pub fn sqlite(&self) -> PathBuf {
+        self.sqlite
+            .clone()
+            .unwrap_or_else(|| self.data_dir.join("wr-log.sqlite3"))
+    }
+
+    pub fn snapshots(&self) -> PathBuf {
+        self.snapshots
+            .clone()
+            .unwrap_or_else(|| self.data_dir.join("snapshots"))
+    }
+
+    /// Where the changelist is exported in the legacy format for the frontend.
+    pub fn changelist_export(&self) -> PathBuf {
+        self.data_dir.join("changelist.json")
+    }
+}
+
+impl Default for Paths {
+    fn default() -> Self {
+        Paths {
+            data_dir: PathBuf::from("/data"),
+            sqlite: None,
+            snapshots: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Steam {
+    pub grpc_server_address: Option<String>,
+    pub web_api_key: Option<String>,
+    /// The app whose workshop is queried.
+    pub app_id: u32,
+    /// Read Steam responses from this fixture file instead of querying Steam.
+    pub fixture_path: Option<PathBuf>,
+}
+
+impl Default for Steam {
+    fn default() -> Self {
+        Steam {
+            grpc_server_address: None,
+            web_api_key: None,
+            app_id: 233610,
+            fixture_path: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Fetch {
+    /// How many leaderboards are fetched at once.
+    pub max_concurrent_requests: usize,
+    /// How long a single workshop listing page or leaderboard request may take before it counts as
+    /// failed.
+    #[serde(with = "humantime_duration")]
+    pub timeout: Duration,
+    /// No more leaderboards are polled after this long. They're polled in order of priority, so
+    /// the ones skipped are the least likely to have changed. `None`, written as `forever`, polls
+    /// every leaderboard.
+    #[serde(with = "humantime_duration_or_forever")]
+    pub time_budget: Option<Duration>,
+    /// How many of the top ranks of each official level's leaderboard are fetched. Only the #1
+    /// makes it into the changelist.
+    pub official_leaderboard_entries: usize,
+    /// How many of the top ranks of each workshop level's leaderboard are fetched.
+    pub workshop_leaderboard_entries: usize,
+    /// How many Steam ids are resolved to names per request.
+    pub name_resolution_chunk_size: usize,
+    /// How long a resolved name is used before it's resolved again, unless the player shows up in
+    /// a leaderboard that changed. Zero resolves every name on every update.
+    #[serde(with = "humantime_duration")]
+    pub name_ttl: Duration,
+    /// How long a level has to be missing from the workshop, or its leaderboard empty, before it's
+    /// considered removed, so that transient Steam query failures aren't reported as deletions.
+    #[serde(with = "humantime_duration")]
+    pub level_removal_grace_period: Duration,
+    /// Between full sweeps, only list the workshop items updated since the last update, and only
+    /// poll the leaderboards that are due.
+    pub incremental: bool,
+    /// How often the whole workshop is listed and every leaderboard polled, even in incremental
+    /// mode.
+    #[serde(with = "humantime_duration")]
+    pub full_sweep_interval: Duration,
+    /// Levels with a changelist event this recent are polled on every update.
+    #[serde(with = "humantime_duration")]
+    pub hot_period: Duration,
+    /// How often the other levels are polled.
+    #[serde(with = "humantime_duration")]
+    pub cold_poll_interval: Duration,
+}
+
+impl Default for Fetch {
+    fn default() -> Self {
+        Fetch {
+            max_concurrent_requests: 4,
+            timeout: Duration::from_secs(60),
+            time_budget: None,
+            official_leaderboard_entries: 2,
+            workshop_leaderboard_entries: 2,
+            name_resolution_chunk_size: 4_096,
+            name_ttl: Duration::ZERO,
+            level_removal_grace_period: Duration::from_secs(24 * 60 * 60),
+            incremental: false,
+            full_sweep_interval: Duration::from_secs(6 * 60 * 60),
+            hot_period: Duration::from_secs(7 * 24 * 60 * 60),
+            cold_poll_interval: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Persistence {
+    pub backend: Backend,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    #[default]
+    Json,
+    Sqlite,
+    Memory,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Snapshots {
+    /// Archive the query results of every update, so the changelist can be replayed from them.
+    pub enabled: bool,
+    /// Every snapshot younger than this is kept.
+    #[serde(with = "humantime_duration")]
+    pub keep_all: Duration,
+    /// Past `keep_all`, the first snapshot of every hour younger than this is kept.
+    #[serde(with = "humantime_duration")]
+    pub keep_hourly: Duration,
+    /// Past `keep_hourly`, the first snapshot of every day younger than this is kept. `None`,
+    /// written as `forever`, keeps a snapshot per day indefinitely.
+    #[serde(with = "humantime_duration_or_forever")]
+    pub keep_daily: Option<Duration>,
+}
+
+impl Default for Snapshots {
+    fn default() -> Self {
+        Snapshots {
+            enabled: false,
+            keep_all: Duration::from_secs(24 * 60 * 60),
+            keep_hourly: Duration::from_secs(30 * 24 * 60 * 60),
+            keep_daily: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Stats {
+    /// Write player profiles and rankings to the data directory after every update.
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RankEvents {
+    /// Add events for new scores below the #1 to the changelist. They're left out of the legacy
+    /// changelist export and the feeds, and only posted to Discord webhooks that list their kinds.
+    pub enabled: bool,
+    /// Players moving into this many top ranks get a `new_top_entry` event, e.g. 3 for the
+    /// podium. Ranks beyond the fetched range are never compared.
+    pub top: usize,
+}
+
+impl Default for RankEvents {
+    fn default() -> Self {
+        RankEvents {
+            enabled: false,
+            top: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Feeds {
+    /// Which feeds to write. Empty disables feeds.
+    pub formats: Vec<FeedFormat>,
+    /// Where the feeds link to, normally the frontend.
+    pub link: String,
+}
+
+impl Default for Feeds {
+    fn default() -> Self {
+        Feeds {
+            formats: Vec::new(),
+            link: "https://github.com/Seeker14491/distance-wr-log-frontend".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FeedFormat {
+    Atom,
+    Rss,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Discord {
+    /// A JSON file listing the webhooks to post changelist events to.
+    pub webhooks_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpApi {
+    /// The address the API listens on. Setting it also makes the manager run the API.
+    pub address: Option<String>,
+}
+
+impl HttpApi {
+    pub const DEFAULT_ADDRESS: &'static str = "0.0.0.0:8080";
+
+    pub fn address(&self) -> &str {
+        self.address.as_deref().unwrap_or(Self::DEFAULT_ADDRESS)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Manager {
+    /// How often the bot is run.
+    #[serde(with = "humantime_duration")]
+    pub update_period: Duration,
+    /// A bot run taking longer than this is considered failed.
+    #[serde(with = "humantime_duration")]
+    pub max_update_duration: Duration,
+    /// The bot executable.
+    pub bot_path: PathBuf,
+    pub healthchecks_url: Option<String>,
+}
+
+impl Default for Manager {
+    fn default() -> Self {
+        Manager {
+            update_period: Duration::from_secs(5 * 60),
+            max_update_duration: Duration::from_secs(60 * 60),
+            bot_path: PathBuf::from("./distance-wr-log-bot"),
+            healthchecks_url: None,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration file named by [`CONFIG_PATH_VAR`], or `/data/config.toml` if it
+    /// exists, and applies the environment variable overrides.
+    pub fn load() -> Result<Config> {
+        let (path, required) = match env::var_os(CONFIG_PATH_VAR) {
+            Some(path) => (PathBuf::from(path), true),
+            None => (PathBuf::from(DEFAULT_CONFIG_PATH), false),
+        };
+
+        let text = match fs::read_to_string(&path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => String::new(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("couldn't read config file {}", path.display()))
+            }
+        };
+
+        Config::parse(&text, |var| env::var(var).ok())
+            .with_context(|| format!("invalid configuration in {}", path.display()))
+    }
+
+    /// Parses a configuration file, with overrides looked up through `env`.
+    pub fn parse(text: &str, env: impl Fn(&str) -> Option<String>) -> Result<Config> {
+        let mut table: toml::Table = text.parse()?;
+
+        let mut overridden = HashMap::new();
+        for &(var, key, kind) in ENV_OVERRIDES {
+            let Some(raw) = env(var) else {
+                continue;
+            };
+            let value = kind
+                .parse(&raw)
+                .with_context(|| format!("`{key}` (set by {var})"))?;
+            let (section, name) = key.split_once('.').unwrap();
+            table
+                .entry(section)
+                .or_insert_with(|| Value::Table(toml::Table::new()))
+                .as_table_mut()
+                .ok_or_else(|| anyhow!("`{section}`: expected a table"))?
+                .insert(name.to_owned(), value);
+            overridden.insert(key, var);
+        }
+
+        let config: Config =
+            serde_path_to_error::deserialize(Value::Table(table)).map_err(|e| {
+                let key = e.path().to_string();
+                match overridden.get(key.as_str()) {
+                    Some(var) => anyhow!("`{key}` (set by {var}): {}", e.inner()),
+                    None => anyhow!("`{key}`: {}", e.inner()),
+                }
+            })?;
+        config
+            .validate()
+            .map_err(|(key, message)| match overridden.get(key) {
+                Some(var) => anyhow!("`{key}` (set by {var}): {message}"),
+                None => anyhow!("`{key}`: {message}"),
+            })?;
+
+        Ok(config)
+    }
+
+    /// Checks the values the types alone don't constrain, returning the offending key.
+    fn validate(&self) -> Result<(), (&'static str, String)> {
+        let at_least_one = |key, value: usize| {
+            if value == 0 {
+                Err((key, "must be at least 1".to_owned()))
+            } else {
+                Ok(())
+            }
+        };
+        let non_zero = |key, value: Duration| {
+            if value.is_zero() {
+                Err((key, "must be longer than zero".to_owned()))
+            } else {
+                Ok(())
+            }
+        };
+
+        if self.steam.app_id == 0 {
+            return Err(("steam.app_id", "must not be 0".to_owned()));
+        }
+        at_least_one(
+            "fetch.max_concurrent_requests",
+            self.fetch.max_concurrent_requests,
+        )?;
+        non_zero("fetch.timeout", self.fetch.timeout)?;
+        if self.fetch.time_budget.is_some_and(|x| x.is_zero()) {
+            return Err(("fetch.time_budget", "must be longer than zero".to_owned()));
+        }
+        at_least_one(
+            "fetch.official_leaderboard_entries",
+            self.fetch.official_leaderboard_entries,
+        )?;
+        at_least_one(
+            "fetch.workshop_leaderboard_entries",
+            self.fetch.workshop_leaderboard_entries,
+        )?;
+        at_least_one(
+            "fetch.name_resolution_chunk_size",
+            self.fetch.name_resolution_chunk_size,
+        )?;
+        non_zero("fetch.full_sweep_interval", self.fetch.full_sweep_interval)?;
+        if self.rank_events.enabled && self.rank_events.top < 2 {
+            return Err(("rank_events.top", "must be at least 2".to_owned()));
+        }
+        if self.snapshots.keep_hourly < self.snapshots.keep_all {
+            return Err((
+                "snapshots.keep_hourly",
+                "must be at least as long as `snapshots.keep_all`".to_owned(),
+            ));
+        }
+        if self
+            .snapshots
+            .keep_daily
+            .is_some_and(|x| x < self.snapshots.keep_hourly)
+        {
+            return Err((
+                "snapshots.keep_daily",
+                "must be at least as long as `snapshots.keep_hourly`".to_owned(),
+            ));
+        }
+        if self.feeds.formats.contains(&FeedFormat::Rss)
+            && !self.feeds.formats.contains(&FeedFormat::Atom)
+        {
+            return Err((
+                "feeds.formats",
+                "RSS feeds are only written alongside Atom feeds".to_owned(),
+            ));
+        }
+        if let Some(address) = &self.http_api.address {
+            if let Err(e) = address.parse::<SocketAddr>() {
+                return Err(("http_api.address", format!("'{address}': {e}")));
+            }
+        }
+        non_zero("manager.update_period", self.manager.update_period)?;
+        non_zero(
+            "manager.max_update_duration",
+            self.manager.max_update_duration,
+        )?;
+
+        Ok(())
+    }
+}
+
+/// How an environment variable's value maps to a TOML value.
+#[derive(Debug, Copy, Clone)]
+enum EnvKind {
+    String,
+    Integer,
+    /// `true` or `false`.
+    Boolean,
+    /// Comma-separated, with `none` meaning an empty list.
+    List,
+}
+
+impl EnvKind {
+    fn parse(self, raw: &str) -> Result<Value> {
+        Ok(match self {
+            EnvKind::String => Value::String(raw.to_owned()),
+            EnvKind::Integer => match raw.trim().parse() {
+                Ok(x) => Value::Integer(x),
+                Err(_) => bail!("expected an integer, found '{raw}'"),
+            },
+            EnvKind::Boolean => match raw.trim() {
+                "true" => Value::Boolean(true),
+                "false" => Value::Boolean(false),
+                _ => bail!("expected 'true' or 'false', found '{raw}'"),
+            },
+            EnvKind::List => Value::Array(match raw.trim() {
+                "" | "none" => Vec::new(),
+                raw => raw
+                    .split(',')
+                    .map(|x| Value::String(x.trim().to_owned()))
+                    .collect(),
+            }),
+        })
+    }
+}
+
+/// Durations are written like `90s`, `5m` or `1h 30m`.
+mod humantime_duration {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer};
+    use std::time::Duration;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        parse::<D::Error>(&String::deserialize(deserializer)?)
+    }
+
+    pub fn parse<E: Error>(text: &str) -> Result<Duration, E> {
+        humantime::parse_duration(text)
+            .map_err(|e| E::custom(format!("invalid duration '{text}': {e}")))
+    }
+}
+
+/// Like [`humantime_duration`], but `forever` means no limit.
+mod humantime_duration_or_forever {
+    use serde::{Deserialize, Deserializer};
+    use std::time::Duration;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        match String::deserialize(deserializer)?.as_str() {
+            "forever" => Ok(None),
+            text => super::humantime_duration::parse(text).map(Some),
+        }
+    }
+}
+
+#[test]
+fn test_parse_config() {
+    let no_env = |_: &str| None;
+    assert_eq!(Config::parse("", no_env).unwrap(), Config::default());
+
+    let text = r#"
+        [paths]
+        data_dir = "/srv/wr-log"
+
+        [fetch]
+        max_concurrent_requests = 8
+        timeout = "2m"
+        time_budget = "10m"
+
+        [snapshots]
+        keep_daily = "1year"
+
+        [manager]
+        update_period = "10m"
+    "#;
+    let env = |var: &str| match var {
+        "FEED_FORMATS" => Some("atom,rss".to_owned()),
+        "WR_LOG_FETCH_MAX_CONCURRENT_REQUESTS" => Some("16".to_owned()),
+        _ => None,
+    };
+    let config = Config::parse(text, env).unwrap();
+    assert_eq!(
+        config.paths.sqlite(),
+        std::path::Path::new("/srv/wr-log/wr-log.sqlite3")
+    );
+    assert_eq!(config.fetch.max_concurrent_requests, 16);
+    assert_eq!(config.fetch.timeout, Duration::from_secs(120));
+    assert_eq!(config.fetch.time_budget, Some(Duration::from_secs(600)));
+    assert_eq!(config.feeds.formats, [FeedFormat::Atom, FeedFormat::Rss]);
+    assert_eq!(config.manager.update_period, Duration::from_secs(600));
+    assert_eq!(
+        config.snapshots.keep_daily,
+        Some(Duration::from_secs(31_557_600))
+    );
+    assert_eq!(config.http_api.address(), HttpApi::DEFAULT_ADDRESS);
+
+    // Errors name the offending key, and the variable if it came from the environment
+    let error = |text: &str, env: &dyn Fn(&str) -> Option<String>| {
+        Config::parse(text, env).unwrap_err().to_string()
+    };
+    assert_eq!(
+        error("[fetch]\nmax_concurrent_requests = 0", &no_env),
+        "`fetch.max_concurrent_requests`: must be at least 1"
+    );
+    assert!(error("[fetch]\ntimeout = \"soon\"", &no_env).starts_with("`fetch.timeout`: "));
+    assert_eq!(
+        error("[fetch]\ntime_budget = \"0s\"", &no_env),
+        "`fetch.time_budget`: must be longer than zero"
+    );
+    assert!(error("[fetch]\nmax_buffer = 4", &no_env).starts_with("`fetch.max_buffer`: "));
+    assert_eq!(
+        error("[rank_events]\nenabled = true\ntop = 1", &no_env),
+        "`rank_events.top`: must be at least 2"
+    );
+    // Settings of disabled features aren't checked
+    assert!(Config::parse("[rank_events]\nenabled = false\ntop = 1", no_env).is_ok());
+    assert_eq!(
+        error("[snapshots]\nkeep_hourly = \"1h\"", &no_env),
+        "`snapshots.keep_hourly`: must be at least as long as `snapshots.keep_all`"
+    );
+    assert!(error("", &|var| (var == "PERSISTENCE_BACKEND")
+        .then(|| "postgres".to_owned()))
+    .starts_with("`persistence.backend` (set by PERSISTENCE_BACKEND): "));
+    assert_eq!(
+        error("", &|var| (var == "HTTP_API_ADDRESS")
+            .then(|| "localhost".to_owned())),
+        "`http_api.address` (set by HTTP_API_ADDRESS): 'localhost': invalid socket address syntax"
+    );
+}
@@ -8,44 +8,45 @@
 use anyhow::{format_err, Context, Error, Result};
 use backoff::backoff::Backoff;
 use backoff::ExponentialBackoff;
+use distance_wr_log_config::{Config, Manager as ManagerConfig};
 use futures::pin_mut;
 use log::{error, info, warn};
 use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::process::ExitStatus;
 use std::time::{Duration, Instant};
-use std::{env, process};
 use tokio::process::Command;
 use tokio::time;
 
-const UPDATE_PERIOD: Duration = Duration::from_secs(5 * 60);
-const MAX_UPDATE_DURATION: Duration = Duration::from_secs(60 * 60);
+const HTTP_API_RESTART_DELAY: Duration = Duration::from_secs(5);
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
     color_backtrace::install();
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
-    let healthchecks_url = match env::var("HEALTHCHECKS_URL") {
-        Ok(x) => Some(x),
+    let config = match Config::load() {
+        Ok(x) => x,
         Err(e) => {
-            match e {
-                env::VarError::NotPresent => {
-                    warn!("Environment variable HEALTHCHECKS_URL is not set")
-                }
-                env::VarError::NotUnicode(_) => {
-                    warn!("Invalid HEALTHCHECKS_URL environment variable");
-                }
-            }
-
-            None
+            print_error(e);
+            process::exit(-1);
         }
     };
+    let healthchecks_url = config.manager.healthchecks_url.as_deref();
+    if healthchecks_url.is_none() {
+        warn!("`manager.healthchecks_url` (HEALTHCHECKS_URL) is not set");
+    }
+
+    if config.http_api.address.is_some() {
+        tokio::spawn(run_http_api(config.manager.bot_path.clone()));
+    }
 
-    let result = run(healthchecks_url.as_deref()).await;
+    let result = run(&config.manager).await;
 
     if let Err(e) = result {
         if let Some(url) = healthchecks_url {
-            healthchecks_send_fail_signal(&url, &format!("error: {e}"))
+            healthchecks_send_fail_signal(url, &format!("error: {e}"))
                 .await
                 .expect("Couldn't send healthchecks fail signal");
         }
@@ -64,23 +65,24 @@ fn print_error<E: Into<Error>>(e: E) {
     }
 }
 
-async fn run(healthchecks_url: Option<&str>) -> Result<()> {
+async fn run(config: &ManagerConfig) -> Result<()> {
     let mut backoff = ExponentialBackoff {
         max_elapsed_time: None,
         ..Default::default()
     };
     loop {
         let update_start_time = Instant::now();
-        let f = run_distance_log();
+        let f = run_distance_log(&config.bot_path);
         pin_mut!(f);
-        match time::timeout(MAX_UPDATE_DURATION, f).await {
+        match time::timeout(config.max_update_duration, f).await {
             Ok(Ok(exit_status)) if exit_status.success() => {
-                if let Some(url) = healthchecks_url {
+                if let Some(url) = &config.healthchecks_url {
                     healthchecks_send_ping(url).await.ok();
                 }
 
                 time::sleep(
-                    UPDATE_PERIOD
+                    config
+                        .update_period
                         .checked_sub(update_start_time.elapsed())
                         .unwrap_or_default(),
                 )
@@ -99,15 +101,38 @@ async fn run(healthchecks_url: Option<&str>) -> Result<()> {
     }
 }
 
-async fn run_distance_log() -> Result<ExitStatus> {
+async fn run_distance_log(bot_path: &Path) -> Result<ExitStatus> {
     info!("Starting distance-wr-log-bot");
-    let mut child = Command::new("./distance-wr-log-bot")
+    let mut child = Command::new(bot_path)
         .spawn()
         .context("Couldn't spawn the distance-wr-log-bot process")?;
 
     Ok(child.wait().await?)
 }
 
+/// Keeps the bot's HTTP API running, restarting it whenever it exits.
+async fn run_http_api(bot_path: PathBuf) {
+    loop {
+        info!("Starting the distance-wr-log-bot HTTP API");
+        let result = Command::new(&bot_path)
+            .arg("serve")
+            .kill_on_drop(true)
+            .spawn()
+            .context("Couldn't spawn the distance-wr-log-bot HTTP API process");
+        match result {
+            Ok(mut child) => match child.wait().await {
+                Ok(exit_status) => print_error(format_err!(
+                    "the distance-wr-log-bot HTTP API exited with {exit_status}"
+                )),
+                Err(e) => print_error(e),
+            },
+            Err(e) => print_error(e),
+        }
+
+        time::sleep(HTTP_API_RESTART_DELAY).await;
+    }
+}
+
 async fn healthchecks_send_ping(healthchecks_url: &str) -> Result<()> {
     let err_msg = "error sending fail signal";
 